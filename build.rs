@@ -1,3 +1,4 @@
+use std::env;
 use std::fs::create_dir_all;
 use std::path::Path;
 use std::process::Command;
@@ -6,6 +7,11 @@ const OUT_FILE: &str = "./lib/CQP.lib";
 const IN_FILE: &str = "./cqp.def";
 
 pub fn main() {
+    // `CQP.dll` only exists on Windows. Other platforms only get the portable
+    // peripherals.
+    if env::var("CARGO_CFG_TARGET_OS").map_or(true, |os| os != "windows") {
+        return
+    }
     let path = Path::new(OUT_FILE);
     if !path.exists() {
         if !path.parent().unwrap().exists() {
@@ -0,0 +1,11 @@
+CREATE TABLE history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    outgoing BOOLEAN NOT NULL,
+    qq BIGINT,
+    grp BIGINT,
+    alias TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    content TEXT NOT NULL
+);
+CREATE INDEX history_by_qq ON history (qq, id);
+CREATE INDEX history_by_grp ON history (grp, id);
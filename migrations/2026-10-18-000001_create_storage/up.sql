@@ -0,0 +1,8 @@
+CREATE TABLE storage (
+    owner TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    scope_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (owner, scope, scope_id, name)
+);
@@ -0,0 +1,20 @@
+-- Dropping columns needs SQLite 3.35, so the table is copied instead.
+-- Messages in discuss groups would look private without the column, so
+-- they are dropped.
+DROP INDEX history_by_discuss;
+CREATE TABLE history_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    outgoing BOOLEAN NOT NULL,
+    qq BIGINT,
+    grp BIGINT,
+    alias TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    content TEXT NOT NULL
+);
+INSERT INTO history_old (id, outgoing, qq, grp, alias, timestamp, content)
+    SELECT id, outgoing, qq, grp, alias, timestamp, content FROM history
+    WHERE discuss IS NULL;
+DROP TABLE history;
+ALTER TABLE history_old RENAME TO history;
+CREATE INDEX history_by_qq ON history (qq, id);
+CREATE INDEX history_by_grp ON history (grp, id);
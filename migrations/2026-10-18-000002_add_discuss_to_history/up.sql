@@ -0,0 +1,2 @@
+ALTER TABLE history ADD COLUMN discuss BIGINT;
+CREATE INDEX history_by_discuss ON history (discuss, id);
@@ -0,0 +1,181 @@
+//! Built-in commands for bot admins, prefixed with `#` so they don't clash
+//! with commands of other backends.
+//!
+//! * `#reload` - Reload config and rebuild all backends.
+//! * `#role <who> [role]` - Show or assign the role of a user. Roles that
+//!   can be assigned are `bot_admin`, `member` and `blacklisted`.
+//!
+//! Commands to answer requests, from `request`, are served here as well, so
+//! that all `#` commands are in the same help.
+use failure::err_msg;
+use backend::{BackendMetadata, Outcome};
+use command::{ArgKind, Command, CommandBackend};
+use msg::text;
+use request;
+use role::{Role, Roles};
+
+pub const IDENTITY: &'static str = "liongbot.admin";
+
+/// Commands to manage the bot, only available to bot admins and owners.
+pub fn admin_backend() -> CommandBackend {
+    let meta = BackendMetadata {
+        identity: IDENTITY,
+        name: "Admin",
+        author: "PENGUINLIONG",
+        description: "Commands to manage the bot.",
+        ..Default::default()
+    };
+    let reload = Command::new("reload", |ctx, _, _| {
+        let dispatcher = ctx.dispatcher()
+            .ok_or_else(|| err_msg("there is no dispatcher to reload"))?;
+        // Only bot admins can see this, and they need to know what's wrong
+        // with the config.
+        let stale = match dispatcher.reload(ctx.peripheral()) {
+            Ok(stale) => stale,
+            Err(err) => {
+                let reply = format!("Reload failed: {}", err);
+                return Ok(Outcome::Fail(text(&reply)))
+            },
+        };
+        let mut report = String::from("Reloaded. Backends:");
+        for meta in dispatcher.backends() {
+            let enabled = dispatcher.is_backend_enabled(meta.identity)
+                .unwrap_or(false);
+            report.push_str(&format!("\n{} ({}{})", meta.identity,
+                                     meta.priority,
+                                     if enabled { "" } else { ", disabled" }));
+        }
+        if !stale.is_empty() {
+            report.push_str(&format!("\nRestart to apply changes to {}.",
+                                     stale.join(", ")));
+        }
+        Ok(Outcome::Reply(text(&report)))
+    })
+        .with_description("Reload config and rebuild all backends.")
+        .with_role(Role::BotAdmin);
+    let role = Command::new("role", |ctx, msg_in, args| {
+        let no_roles = Roles::new();
+        let roles = ctx.roles().unwrap_or(&no_roles);
+        let who = args.at("who").unwrap();
+        let current = roles.bot_role(ctx, who)?;
+        let role = match args.str("role") {
+            Some(name) => match Role::from_name(name) {
+                Ok(role) => role,
+                Err(err) => return Ok(Outcome::Fail(text(&err.to_string()))),
+            },
+            None => {
+                let reply = format!("{} is a {}.", who, current);
+                return Ok(Outcome::Reply(text(&reply)))
+            },
+        };
+        // Nobody can give others a role as high as their own, or change
+        // the role of someone as high as them.
+        if ctx.role_of(msg_in)? <= ::std::cmp::max(current, role) {
+            let reply = format!("Sorry, you can't make {} a {}.", who, role);
+            return Ok(Outcome::Fail(text(&reply)))
+        }
+        roles.assign(ctx, who, role)?;
+        let actual = roles.bot_role(ctx, who)?;
+        let mut reply = format!("{} is now a {}.", who, actual);
+        if actual != role {
+            reply.push_str(" The role is set in config.");
+        }
+        Ok(Outcome::Reply(text(&reply)))
+    })
+        .with_description("Show or assign the role of a user.")
+        .with_arg("who", ArgKind::At)
+        .with_optional_arg("role", ArgKind::Word)
+        .with_role(Role::BotAdmin);
+    let mut backend = CommandBackend::new(meta, "#")
+        .with_command(reload)
+        .with_command(role);
+    for command in request::commands() {
+        backend.add_command(command);
+    }
+    backend
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use dispatcher::Dispatcher;
+    use msg::{at, Msg, MsgBuilder, MsgIn};
+    use peripheral::memory::MemoryPeripheral;
+    use role::refusal;
+
+    fn make_msg_in(qq: i64, content: Msg) -> MsgIn {
+        MsgIn::Private {
+            qq: qq,
+            alias: qq.to_string(),
+            content: content,
+        }
+    }
+    fn make_dispatcher() -> Dispatcher {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_roles(Roles::new().with_owner(1).with_admin(2))
+            .use_backend(admin_backend(), 100);
+        dispatcher
+    }
+    #[test]
+    fn test_reload() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = make_dispatcher();
+        let fail = Rc::new(Cell::new(false));
+        let fail2 = fail.clone();
+        dispatcher.use_reloader(move |dispatcher| {
+            if fail2.get() {
+                return Err(err_msg("broken config"))
+            }
+            dispatcher
+                .note_restart_needed("owners and admins")
+                .use_backend(admin_backend(), 10);
+            Ok(())
+        });
+        let reload = |qq| {
+            dispatcher.dispatch(&peri, &make_msg_in(qq, text("#reload")))
+        };
+        assert_eq!(reload(3), Outcome::Fail(refusal(Role::BotAdmin)));
+        assert_eq!(reload(2), Outcome::Reply(text("Reloaded. Backends:\n\
+                                                   liongbot.admin (10)\n\
+                                                   Restart to apply changes \
+                                                   to owners and admins.")));
+        fail.set(true);
+        assert_eq!(reload(2),
+                   Outcome::Fail(text("Reload failed: broken config")));
+        assert_eq!(dispatcher.backends()[0].priority, 10);
+    }
+    #[test]
+    fn test_role() {
+        let peri = MemoryPeripheral::new();
+        let dispatcher = make_dispatcher();
+        let role = |qq, who, role: &str| {
+            let content = msg!["#role ", at(who), text(role)];
+            match dispatcher.dispatch(&peri, &make_msg_in(qq, content)) {
+                Outcome::Reply(Msg::Text(x)) => Ok(x),
+                Outcome::Fail(Msg::Text(x)) => Err(x),
+                x => panic!("unexpected outcome {:?}", x),
+            }
+        };
+        assert_eq!(role(2, 3, " blacklisted"),
+                   Ok("3 is now a blacklisted user.".to_owned()));
+        // Blacklisted users are ignored.
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in(3, text("#role"))),
+                   Outcome::Pass);
+        assert_eq!(role(2, 4, " bot_admin"),
+                   Err("Sorry, you can't make 4 a bot admin.".to_owned()));
+        assert_eq!(role(1, 4, " bot_admin"),
+                   Ok("4 is now a bot admin.".to_owned()));
+        assert_eq!(role(4, 3, ""),
+                   Ok("3 is a blacklisted user.".to_owned()));
+        assert_eq!(role(1, 2, " member"),
+                   Ok("2 is now a bot admin. The role is set in config."
+                      .to_owned()));
+        assert_eq!(role(2, 1, " member"),
+                   Err("Sorry, you can't make 1 a member.".to_owned()));
+        assert!(role(1, 3, " bot_owner").is_err());
+    }
+}
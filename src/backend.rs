@@ -1,17 +1,289 @@
-use {Msg, MsgIn};
-use failure::Error;
+use {Composer, Msg, MsgIn};
+use group::GroupAdmin;
+use msg::Conversation;
+use std::time::Instant;
+use failure::{err_msg, Error};
+use serde_json::Value;
+use dispatcher::Dispatcher;
+use history::History;
+use limit::RateLimiter;
+use middleware::Middleware;
+use peripheral::{AdminError, Event, Lifecycle, MemberInfo, Peripheral,
+                 UserInfo};
+use role::{Role, Roles};
+use storage::{Storage, Store};
+use unwind;
 
+#[derive(Clone, Debug, Default)]
 pub struct BackendMetadata {
     pub identity: &'static str,
     pub name: &'static str,
     pub author: &'static str,
     pub version: &'static str,
     pub description: &'static str,
+    /// Priority the backend is registered with. Backends of higher priority
+    /// are previewed before others. Backends don't need to fill it, the
+    /// dispatcher does.
+    pub priority: i32,
+}
+
+/// What a backend has done to an incoming message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// Reply with a message.
+    Reply(Msg),
+    /// Reply with several messages, in order.
+    Replies(Vec<Msg>),
+    /// The message is consumed but there is nothing to reply.
+    Handled,
+    /// The message is not for this backend, pass it to the next backend.
+    Pass,
+    /// The message is consumed but processing failed. The failure message is
+    /// shown to the user.
+    Fail(Msg),
+}
+impl Outcome {
+    /// Whether the message has been consumed by a backend, i.e. no one else
+    /// should handle it again.
+    pub fn is_consumed(&self) -> bool {
+        *self != Outcome::Pass
+    }
+    /// Messages to be sent back to the user.
+    pub fn into_replies(self) -> Vec<Msg> {
+        match self {
+            Outcome::Reply(msg) => vec![msg],
+            Outcome::Replies(msgs) => msgs,
+            Outcome::Fail(msg) => vec![msg],
+            Outcome::Handled | Outcome::Pass => Vec::new(),
+        }
+    }
+}
+
+/// Handle to the platform the bot is running on, given to backends so they
+/// can do more than replying.
+#[derive(Clone)]
+pub struct Context<'a> {
+    peripheral: &'a Peripheral,
+    composer: &'a Composer,
+    dispatcher: Option<&'a Dispatcher>,
+    history: Option<&'a History>,
+    limiter: Option<&'a RateLimiter>,
+    middlewares: &'a [Box<Middleware>],
+    roles: Option<&'a Roles>,
+    storage: Option<&'a Storage>,
+    /// Identity of the backend the context is given to.
+    owner: &'a str,
+}
+impl<'a> Context<'a> {
+    pub fn new(peripheral: &'a Peripheral, composer: &'a Composer)
+            -> Context<'a> {
+        Context {
+            peripheral: peripheral,
+            composer: composer,
+            dispatcher: None,
+            history: None,
+            limiter: None,
+            middlewares: &[],
+            roles: None,
+            storage: None,
+            owner: "",
+        }
+    }
+    pub fn with_dispatcher(mut self, dispatcher: &'a Dispatcher)
+            -> Context<'a> {
+        self.dispatcher = Some(dispatcher);
+        self
+    }
+    /// Record messages sent through this context in `history`.
+    pub fn with_history(mut self, history: &'a History) -> Context<'a> {
+        self.history = Some(history);
+        self
+    }
+    /// Limit the rate of messages sent through this context by the global
+    /// bucket of `limiter`.
+    pub fn with_limiter(mut self, limiter: &'a RateLimiter) -> Context<'a> {
+        self.limiter = Some(limiter);
+        self
+    }
+    /// Transform outgoing messages with `middlewares`.
+    pub fn with_middlewares(mut self, middlewares: &'a [Box<Middleware>])
+            -> Context<'a> {
+        self.middlewares = middlewares;
+        self
+    }
+    pub fn with_roles(mut self, roles: &'a Roles) -> Context<'a> {
+        self.roles = Some(roles);
+        self
+    }
+    pub fn with_storage(mut self, storage: &'a Storage) -> Context<'a> {
+        self.storage = Some(storage);
+        self
+    }
+    /// Make the context for the backend of the given identity.
+    pub fn with_owner(mut self, owner: &'a str) -> Context<'a> {
+        self.owner = owner;
+        self
+    }
+    pub fn peripheral(&self) -> &'a Peripheral {
+        self.peripheral
+    }
+    pub fn composer(&self) -> &'a Composer {
+        self.composer
+    }
+    /// The dispatcher the message is dispatched by, if any.
+    pub fn dispatcher(&self) -> Option<&'a Dispatcher> {
+        self.dispatcher
+    }
+    /// Message history, if the bot keeps one.
+    pub fn history(&self) -> Option<&'a History> {
+        self.history
+    }
+    /// Roles given in config, if any.
+    pub fn roles(&self) -> Option<&'a Roles> {
+        self.roles
+    }
+    /// Role of the sender of `msg_in`.
+    pub fn role_of(&self, msg_in: &MsgIn) -> Result<Role, Error> {
+        match self.roles {
+            Some(roles) => roles.role_of(self, msg_in),
+            None => Roles::new().role_of(self, msg_in),
+        }
+    }
+    /// Check whether the sender of `msg_in` has the `required` role.
+    pub fn has_role(&self, msg_in: &MsgIn, required: Role)
+            -> Result<bool, Error> {
+        match self.roles {
+            Some(roles) => roles.check(self, msg_in, required),
+            None => Roles::new().check(self, msg_in, required),
+        }
+    }
+    /// Storage private to the backend.
+    pub fn store(&self) -> Store<'a> {
+        Store::new(self.storage, self.owner)
+    }
+
+    /// Pass an outgoing message through middlewares, in reverse order. A
+    /// panicking middleware fails the message.
+    fn transform(&self, msg: &Msg) -> Result<Msg, Error> {
+        let mut msg = msg.clone();
+        for middleware in self.middlewares.iter().rev() {
+            let name = middleware.name();
+            let ctx = self.clone().with_owner(name);
+            msg = match unwind::catch(name, || middleware.after(&ctx, msg)) {
+                Ok(rv) => rv?,
+                Err(_) => {
+                    return Err(err_msg(format!("middleware `{}` panicked",
+                                               name)))
+                },
+            };
+        }
+        Ok(msg)
+    }
+    /// Take a token for an outgoing message.
+    fn check_send(&self) -> Result<(), Error> {
+        match self.limiter {
+            Some(limiter) if !limiter.check_send(Instant::now()) => {
+                Err(err_msg("too many messages are being sent"))
+            },
+            _ => Ok(()),
+        }
+    }
+    pub fn send_priv(&self, qq: i64, msg: &Msg) -> Result<(), Error> {
+        self.check_send()?;
+        let msg = self.transform(msg)?;
+        let raw = self.composer.compose(&msg)?;
+        self.peripheral.send_priv(qq, &raw)?;
+        if let Some(history) = self.history {
+            // The message has been sent anyway, failing to record it is not
+            // the sender's problem.
+            if let Err(err) = history.record_priv_out(qq, &msg) {
+                warn!("unable to record message to {}: {}", qq, err);
+            }
+        }
+        Ok(())
+    }
+    pub fn send_grp(&self, grp: i64, msg: &Msg) -> Result<(), Error> {
+        self.check_send()?;
+        let msg = self.transform(msg)?;
+        let raw = self.composer.compose(&msg)?;
+        self.peripheral.send_grp(grp, &raw)?;
+        if let Some(history) = self.history {
+            if let Err(err) = history.record_grp_out(grp, &msg) {
+                warn!("unable to record message to group {}: {}", grp, err);
+            }
+        }
+        Ok(())
+    }
+    pub fn send_discuss(&self, discuss: i64, msg: &Msg) -> Result<(), Error> {
+        self.check_send()?;
+        let msg = self.transform(msg)?;
+        let raw = self.composer.compose(&msg)?;
+        self.peripheral.send_discuss(discuss, &raw)?;
+        if let Some(history) = self.history {
+            if let Err(err) = history.record_discuss_out(discuss, &msg) {
+                warn!("unable to record message to discuss {}: {}", discuss,
+                      err);
+            }
+        }
+        Ok(())
+    }
+    /// Send a message to a conversation.
+    pub fn send(&self, conv: Conversation, msg: &Msg) -> Result<(), Error> {
+        match conv {
+            Conversation::Private(qq) => self.send_priv(qq, msg),
+            Conversation::Group(grp) => self.send_grp(grp, msg),
+            Conversation::Discuss(discuss) => self.send_discuss(discuss, msg),
+        }
+    }
+    /// Send a message to where the incoming message came from.
+    pub fn reply(&self, msg_in: &MsgIn, msg: &Msg) -> Result<(), Error> {
+        self.send(msg_in.conversation(), msg)
+    }
+    pub fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+        self.peripheral.user_info(qq)
+    }
+    pub fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+        self.peripheral.member_info(grp, qq)
+    }
+    /// Administrate a group.
+    pub fn group(&self, grp: i64) -> GroupAdmin<'a> {
+        GroupAdmin::new(self.peripheral, grp)
+    }
+    /// Leave a discuss group.
+    pub fn leave_discuss(&self, discuss: i64) -> Result<(), AdminError> {
+        self.peripheral.leave_discuss(discuss)
+    }
 }
 
 pub trait Backend {
     fn metadata(&self) -> BackendMetadata;
+    /// Apply the settings section of the backend in config. `Value::Null` is
+    /// given if there is none. Use `config::parse_settings` to deserialize
+    /// it.
+    fn configure(&mut self, _settings: &Value) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Role a user needs to use the backend. Users without the role are
+    /// politely refused.
+    fn required_role(&self) -> Role {
+        Role::Member
+    }
+    /// Whether anonymous group members can use the backend. They can't be
+    /// held accountable, so they are turned away by default. Config can
+    /// override it.
+    fn accepts_anonymous(&self) -> bool {
+        false
+    }
     fn preview(&self, msg_in: &MsgIn) -> bool;
-    /// Process message and give a response.
-    fn process(&self, msg_in: &MsgIn) -> Result<Msg, Error>;
+    /// Process message and give a response. An `Err` is logged, and the user
+    /// is only told something went wrong. Return `Outcome::Fail` to tell the
+    /// user why.
+    fn process(&self, ctx: &Context, msg_in: &MsgIn) -> Result<Outcome, Error>;
+    /// Called on lifecycle events of the bot.
+    fn on_lifecycle(&self, _ctx: &Context, _event: Lifecycle) {}
+    /// Act on an event other than messages. Return `true` if the event is
+    /// handled, so that it doesn't reach backends of lower priorities.
+    fn on_event(&self, _ctx: &Context, _event: &Event) -> Result<bool, Error> {
+        Ok(false)
+    }
 }
@@ -0,0 +1,38 @@
+//! Run the bot in a local console, without CoolQ.
+extern crate liongbot;
+extern crate log;
+
+use std::env;
+use std::io;
+use std::process;
+use log::LevelFilter;
+use liongbot::dispatcher::Dispatcher;
+use liongbot::peripheral::console::Console;
+use liongbot::peripheral::coolq::CoolQComposer;
+
+fn main() {
+    // Logs go to stderr, so they don't mix with the conversation.
+    liongbot::logger::init_stderr(LevelFilter::Info).unwrap();
+    liongbot::on_launch();
+    let mut dispatcher = Dispatcher::new();
+    let rv = env::current_dir()
+        .map_err(Into::into)
+        .and_then(|dir| {
+            // CQ codes are as readable as anything in a console.
+            let composer = CoolQComposer::new(&dir);
+            liongbot::on_configure(&mut dispatcher, &dir, composer)
+        });
+    if let Err(err) = rv {
+        eprintln!("unable to configure: {}", err);
+        process::exit(1);
+    }
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let rv = Console::new(stdout.lock()).run(&dispatcher, stdin.lock());
+    liongbot::on_shutdown();
+    if let Err(err) = rv {
+        eprintln!("console stopped unexpectedly: {}", err);
+        process::exit(1);
+    }
+}
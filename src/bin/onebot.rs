@@ -0,0 +1,45 @@
+//! Run the bot as a reverse-WebSocket server for OneBot implementations.
+//!
+//! Usage: `liongbot-onebot [ADDR]`, where `ADDR` defaults to
+//! `127.0.0.1:6700`.
+extern crate liongbot;
+extern crate log;
+
+use std::env;
+use std::process;
+use log::LevelFilter;
+use liongbot::dispatcher::Dispatcher;
+use liongbot::peripheral::onebot::{OneBot, OneBotComposer};
+
+fn main() {
+    liongbot::logger::init_stderr(LevelFilter::Info).unwrap();
+    let addr = env::args().nth(1)
+        .unwrap_or_else(|| "127.0.0.1:6700".to_owned());
+    let onebot = match OneBot::bind(&addr) {
+        Ok(onebot) => onebot,
+        Err(err) => {
+            eprintln!("unable to listen on {}: {}", addr, err);
+            process::exit(1);
+        },
+    };
+
+    liongbot::on_launch();
+    let mut dispatcher = Dispatcher::new();
+    let rv = env::current_dir()
+        .map_err(Into::into)
+        .and_then(|dir| {
+            liongbot::on_configure(&mut dispatcher, &dir,
+                                   OneBotComposer::new())
+        });
+    if let Err(err) = rv {
+        eprintln!("unable to configure: {}", err);
+        process::exit(1);
+    }
+
+    let rv = onebot.serve(&dispatcher);
+    liongbot::on_shutdown();
+    if let Err(err) = rv {
+        eprintln!("onebot server stopped unexpectedly: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,525 @@
+//! Command backend which parses messages like `!roll 6 "two dice" @someone`
+//! into named commands with typed arguments.
+use std::fmt;
+use failure::{err_msg, Error};
+use {Backend, Msg, MsgIn};
+use backend::{BackendMetadata, Context, Outcome};
+use msg::MsgBuilder;
+use role::{self, Role};
+
+/// A piece of a command line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Token {
+    /// Text delimited by whitespaces.
+    Word(String),
+    /// Text in double quotes.
+    Quoted(String),
+    /// Mentioning someone, from an `at` segment.
+    At(i64),
+    /// Any other non-text segment.
+    Ext(Msg),
+}
+
+/// Split a message into tokens. Double quotes group text containing
+/// whitespaces into a single token, in which `\"` and `\\` are escaped.
+pub fn tokenize(msg: &Msg) -> Result<Vec<Token>, Error> {
+    let mut rv = Vec::new();
+    tokenize_impl(msg, &mut rv)?;
+    Ok(rv)
+}
+fn tokenize_impl(msg: &Msg, out: &mut Vec<Token>) -> Result<(), Error> {
+    match msg {
+        Msg::Text(ref content) => tokenize_text(content, out)?,
+        Msg::Compound(ref segs) => {
+            for seg in segs {
+                tokenize_impl(seg, out)?;
+            }
+        },
+        Msg::Ext { ref name, ref params } => {
+            let qq = params.get("qq")
+                .and_then(|qq| qq.parse::<i64>().ok());
+            match (name.as_ref(), qq) {
+                ("at", Some(qq)) => out.push(Token::At(qq)),
+                _ => out.push(Token::Ext(msg.clone())),
+            }
+        },
+    }
+    Ok(())
+}
+/// The first word of a message, delimited like tokens are, if the message
+/// starts with text.
+fn first_word(msg: &Msg) -> Option<&str> {
+    match msg {
+        Msg::Text(ref content) => {
+            content.split(|c: char| c.is_whitespace() || c == '"')
+                .next()
+                .filter(|word| !word.is_empty())
+        },
+        Msg::Compound(ref segs) => segs.first().and_then(first_word),
+        _ => None,
+    }
+}
+fn tokenize_text(text: &str, out: &mut Vec<Token>) -> Result<(), Error> {
+    let mut chars = text.chars();
+    let mut word = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if !word.is_empty() {
+                    out.push(Token::Word(word.clone()));
+                    word.clear();
+                }
+                let mut quoted = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break
+                        },
+                        '\\' => match chars.next() {
+                            Some(c @ '"') | Some(c @ '\\') => quoted.push(c),
+                            Some(c) => {
+                                quoted.push('\\');
+                                quoted.push(c);
+                            },
+                            None => quoted.push('\\'),
+                        },
+                        c => quoted.push(c),
+                    }
+                }
+                if !closed {
+                    return Err(err_msg("unclosed quotation mark"))
+                }
+                out.push(Token::Quoted(quoted));
+            },
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    out.push(Token::Word(word.clone()));
+                    word.clear();
+                }
+            },
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        out.push(Token::Word(word));
+    }
+    Ok(())
+}
+
+/// Type of a command argument.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgKind {
+    /// A single word without quotes.
+    Word,
+    /// A single word or a quoted string.
+    Str,
+    /// A decimal integer.
+    Int,
+    /// Mentioning someone.
+    At,
+}
+impl fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ArgKind::Word => "word",
+            ArgKind::Str => "text",
+            ArgKind::Int => "int",
+            ArgKind::At => "@",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Value of a parsed argument.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Arg {
+    Str(String),
+    Int(i64),
+    At(i64),
+}
+
+struct ArgSpec {
+    name: &'static str,
+    kind: ArgKind,
+    optional: bool,
+}
+
+/// Arguments parsed from a command line.
+#[derive(Clone, Debug, Default)]
+pub struct Args(Vec<(&'static str, Arg)>);
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&Arg> {
+        self.0.iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, arg)| arg)
+    }
+    pub fn str(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(Arg::Str(ref x)) => Some(x),
+            _ => None,
+        }
+    }
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(Arg::Int(x)) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn at(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(Arg::At(x)) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+type Handler = Box<Fn(&Context, &MsgIn, &Args) -> Result<Outcome, Error>>;
+
+/// A named command with aliases and typed arguments.
+pub struct Command {
+    name: String,
+    aliases: Vec<String>,
+    description: String,
+    args: Vec<ArgSpec>,
+    role: Role,
+    handler: Handler,
+}
+impl Command {
+    pub fn new<F>(name: &str, handler: F) -> Command
+            where F: 'static +
+                Fn(&Context, &MsgIn, &Args) -> Result<Outcome, Error> {
+        Command {
+            name: name.to_owned(),
+            aliases: Vec::new(),
+            description: String::new(),
+            args: Vec::new(),
+            role: Role::Member,
+            handler: Box::new(handler),
+        }
+    }
+    pub fn with_alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_owned());
+        self
+    }
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = description.to_owned();
+        self
+    }
+    /// Only allow users of `role` or above to run the command.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+    /// Append a required argument. Required arguments cannot follow optional
+    /// ones.
+    pub fn with_arg(mut self, name: &'static str, kind: ArgKind) -> Self {
+        assert!(!self.args.iter().any(|arg| arg.optional),
+                "required argument `{}` follows an optional one", name);
+        self.args.push(ArgSpec { name: name, kind: kind, optional: false });
+        self
+    }
+    /// Append an optional argument.
+    pub fn with_optional_arg(mut self, name: &'static str, kind: ArgKind)
+            -> Self {
+        self.args.push(ArgSpec { name: name, kind: kind, optional: true });
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+    /// Usage line like `!roll <sides:int> [times:int]`.
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut rv = format!("{}{}", prefix, self.name);
+        for arg in self.args.iter() {
+            if arg.optional {
+                rv.push_str(&format!(" [{}:{}]", arg.name, arg.kind));
+            } else {
+                rv.push_str(&format!(" <{}:{}>", arg.name, arg.kind));
+            }
+        }
+        rv
+    }
+    fn parse_args(&self, tokens: &[Token]) -> Result<Args, Error> {
+        if tokens.len() > self.args.len() {
+            return Err(err_msg("too many arguments"))
+        }
+        let mut rv = Vec::with_capacity(tokens.len());
+        for (i, spec) in self.args.iter().enumerate() {
+            let token = match tokens.get(i) {
+                Some(token) => token,
+                None if spec.optional => break,
+                None => {
+                    return Err(err_msg(format!("missing `{}`", spec.name)))
+                },
+            };
+            let arg = match (spec.kind, token) {
+                (ArgKind::Word, Token::Word(ref x)) => Arg::Str(x.clone()),
+                (ArgKind::Str, Token::Word(ref x)) |
+                (ArgKind::Str, Token::Quoted(ref x)) => Arg::Str(x.clone()),
+                (ArgKind::Int, Token::Word(ref x)) => {
+                    let x = x.parse::<i64>()
+                        .map_err(|_| {
+                            err_msg(format!("`{}` should be an integer",
+                                            spec.name))
+                        })?;
+                    Arg::Int(x)
+                },
+                (ArgKind::At, Token::At(qq)) => Arg::At(*qq),
+                (kind, _) => {
+                    return Err(err_msg(format!("`{}` should be {}",
+                                               spec.name, kind)))
+                },
+            };
+            rv.push((spec.name, arg));
+        }
+        Ok(Args(rv))
+    }
+}
+
+/// Backend routing messages starting with a prefix to registered commands.
+/// A `help` command listing all the commands is generated automatically,
+/// unless a command of that name is registered.
+pub struct CommandBackend {
+    metadata: BackendMetadata,
+    prefix: String,
+    commands: Vec<Command>,
+}
+impl CommandBackend {
+    pub fn new(metadata: BackendMetadata, prefix: &str) -> CommandBackend {
+        CommandBackend {
+            metadata: metadata,
+            prefix: prefix.to_owned(),
+            commands: Vec::new(),
+        }
+    }
+    pub fn add_command(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+    pub fn with_command(mut self, command: Command) -> Self {
+        self.add_command(command);
+        self
+    }
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn find_command(&self, name: &str) -> Option<&Command> {
+        self.commands.iter()
+            .find(|cmd| cmd.matches(name))
+    }
+    /// Extract the command name from the first word of a message.
+    fn command_name<'a>(&self, msg: &'a Msg) -> Option<&'a str> {
+        match first_word(msg) {
+            Some(word) if word.starts_with(&self.prefix) => {
+                Some(&word[self.prefix.len()..])
+            },
+            _ => None,
+        }
+    }
+    /// Help listing of all commands, or the usage of a single command.
+    pub fn help(&self, name: Option<&str>) -> Msg {
+        if let Some(name) = name {
+            if let Some(cmd) = self.find_command(name) {
+                return self.describe(cmd)
+            }
+            return ::msg::text(&format!("Unknown command `{}`.", name))
+        }
+        let mut rv = MsgBuilder::new()
+            .with_msg(Msg::from("Commands:"));
+        for cmd in self.commands.iter() {
+            rv.add_msg(Msg::from("\n"));
+            rv.add_msg(self.describe(cmd));
+        }
+        rv.build()
+    }
+    fn describe(&self, cmd: &Command) -> Msg {
+        let mut rv = cmd.usage(&self.prefix);
+        if !cmd.aliases.is_empty() {
+            rv.push_str(&format!(" (alias: {})", cmd.aliases.join(", ")));
+        }
+        if !cmd.description.is_empty() {
+            rv.push_str(" - ");
+            rv.push_str(&cmd.description);
+        }
+        Msg::Text(rv)
+    }
+    fn is_help(&self, name: &str) -> bool {
+        name == "help" && self.find_command(name).is_none()
+    }
+}
+impl Backend for CommandBackend {
+    fn metadata(&self) -> BackendMetadata {
+        self.metadata.clone()
+    }
+    fn preview(&self, msg_in: &MsgIn) -> bool {
+        // Unclosed quotes are still worth a usage line, so only look at the
+        // first word here, and leave the rest to `process`.
+        match self.command_name(msg_in.content()) {
+            Some(name) => {
+                self.is_help(name) || self.find_command(name).is_some()
+            },
+            None => false,
+        }
+    }
+    fn process(&self, ctx: &Context, msg_in: &MsgIn)
+            -> Result<Outcome, Error> {
+        let name = match self.command_name(msg_in.content()) {
+            Some(name) => name,
+            None => return Ok(Outcome::Pass),
+        };
+        let tokens = tokenize(msg_in.content());
+        if self.is_help(name) {
+            let topic = match tokens.as_ref().ok().and_then(|t| t.get(1)) {
+                Some(Token::Word(ref x)) => {
+                    Some(x.trim_start_matches(self.prefix.as_str()))
+                },
+                _ => None,
+            };
+            return Ok(Outcome::Reply(self.help(topic)))
+        }
+        let cmd = match self.find_command(name) {
+            Some(cmd) => cmd,
+            None => return Ok(Outcome::Pass),
+        };
+        // Blacklisted users are already stopped by the dispatcher.
+        if cmd.role > Role::Member && !ctx.has_role(msg_in, cmd.role)? {
+            return Ok(Outcome::Fail(role::refusal(cmd.role)))
+        }
+        let args = tokens.and_then(|tokens| {
+            cmd.parse_args(tokens.get(1..).unwrap_or(&[]))
+        });
+        match args {
+            Ok(args) => (cmd.handler)(ctx, msg_in, &args),
+            Err(err) => {
+                let usage = cmd.usage(&self.prefix);
+                Ok(Outcome::Fail(::msg::text(&format!("{}\nUsage: {}",
+                                                      err, usage))))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::*;
+    use peripheral::memory::MemoryPeripheral;
+
+    fn make_backend() -> CommandBackend {
+        let meta = BackendMetadata {
+            identity: "test.command",
+            ..Default::default()
+        };
+        let roll = Command::new("roll", |_, _, args| {
+            let sides = args.int("sides").unwrap();
+            let times = args.int("times").unwrap_or(1);
+            Ok(Outcome::Reply(text(&format!("{}d{}", times, sides))))
+        })
+            .with_alias("r")
+            .with_description("Roll dice.")
+            .with_arg("sides", ArgKind::Int)
+            .with_optional_arg("times", ArgKind::Int);
+        let poke = Command::new("poke", |_, _, args| {
+            Ok(Outcome::Reply(msg![at(args.at("who").unwrap()),
+                                   text(args.str("words").unwrap())]))
+        })
+            .with_arg("who", ArgKind::At)
+            .with_arg("words", ArgKind::Str);
+        CommandBackend::new(meta, "!")
+            .with_command(roll)
+            .with_command(poke)
+    }
+    fn make_msg_in(content: Msg) -> MsgIn {
+        MsgIn::Private {
+            qq: 1,
+            alias: "1".to_owned(),
+            content: content,
+        }
+    }
+    fn run(backend: &CommandBackend, content: Msg) -> Option<Outcome> {
+        let peri = MemoryPeripheral::new();
+        let composer = ::peripheral::coolq::CoolQComposer::new("C:/");
+        let ctx = Context::new(&peri, &composer);
+        let msg_in = make_msg_in(content);
+        if backend.preview(&msg_in) {
+            Some(backend.process(&ctx, &msg_in).unwrap())
+        } else {
+            None
+        }
+    }
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize(&msg!["!say  \"a \\\"b\\\" c\"d", at(1), " 2"]);
+        assert_eq!(tokens.unwrap(), vec![
+            Token::Word("!say".to_owned()),
+            Token::Quoted("a \"b\" c".to_owned()),
+            Token::Word("d".to_owned()),
+            Token::At(1),
+            Token::Word("2".to_owned()),
+        ]);
+        assert!(tokenize(&text("\"unclosed")).is_err());
+    }
+    #[test]
+    fn test_dispatch_command() {
+        let backend = make_backend();
+        assert_eq!(run(&backend, text("!roll 6")),
+                   Some(Outcome::Reply(text("1d6"))));
+        assert_eq!(run(&backend, text("!r 6 2")),
+                   Some(Outcome::Reply(text("2d6"))));
+        assert_eq!(run(&backend, msg!["!poke ", at(2), " \"hi there\""]),
+                   Some(Outcome::Reply(msg![at(2), "hi there"])));
+        assert_eq!(run(&backend, text("roll 6")), None);
+        assert_eq!(run(&backend, text("!unknown")), None);
+    }
+    #[test]
+    fn test_usage() {
+        let backend = make_backend();
+        let usage = "`sides` should be an integer\n\
+                     Usage: !roll <sides:int> [times:int]";
+        assert_eq!(run(&backend, text("!roll six")),
+                   Some(Outcome::Fail(text(usage))));
+        let usage = "`who` should be @\nUsage: !poke <who:@> <words:text>";
+        assert_eq!(run(&backend, text("!poke someone hi")),
+                   Some(Outcome::Fail(text(usage))));
+        let usage = "missing `words`\nUsage: !poke <who:@> <words:text>";
+        assert_eq!(run(&backend, msg!["!poke ", at(2)]),
+                   Some(Outcome::Fail(text(usage))));
+        let usage = "unclosed quotation mark\n\
+                     Usage: !poke <who:@> <words:text>";
+        assert_eq!(run(&backend, msg!["!poke ", at(2), " \"hi there"]),
+                   Some(Outcome::Fail(text(usage))));
+        let usage = "unclosed quotation mark\n\
+                     Usage: !roll <sides:int> [times:int]";
+        assert_eq!(run(&backend, text("!roll \"6")),
+                   Some(Outcome::Fail(text(usage))));
+    }
+    #[test]
+    fn test_help() {
+        let backend = make_backend();
+        let help = "Commands:\n\
+                    !roll <sides:int> [times:int] (alias: r) - Roll dice.\n\
+                    !poke <who:@> <words:text>";
+        assert_eq!(run(&backend, text("!help")),
+                   Some(Outcome::Reply(text(help))));
+        assert_eq!(run(&backend, text("!help !r")), Some(Outcome::Reply(
+            text("!roll <sides:int> [times:int] (alias: r) - Roll dice."))));
+    }
+}
@@ -0,0 +1,248 @@
+//! Bot configuration, loaded from `liongbot.toml` or `liongbot.json` in the
+//! app directory. For example:
+//!
+//! ```toml
+//! database = "liongbot.db"
+//! owners = [10000]
+//! admins = [10001]
+//!
+//! [limits]
+//! user = { burst = 5, per_minute = 10 }
+//! global = { burst = 20, per_minute = 60 }
+//!
+//! [backends."moe.penguinliong.roll"]
+//! priority = 10
+//! anonymous = true
+//! settings = { max_sides = 100 }
+//!
+//! [backends."moe.penguinliong.chat"]
+//! enabled = false
+//! limits = { group = { burst = 3, per_minute = 6 } }
+//! ```
+//!
+//! Environment variables, and those in `.env` of the app directory, override
+//! the file:
+//!
+//! * `LIONGBOT_DATABASE` - Path to the database.
+//! * `LIONGBOT_ENABLE` - Comma-separated identities of backends to enable.
+//! * `LIONGBOT_DISABLE` - Comma-separated identities of backends to disable.
+//!
+//! Backends are reloaded when any of the files is changed, but changes to
+//! `database`, `owners`, `admins` and the shared `limits` need a restart.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use dotenv;
+use failure::{err_msg, Error};
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use toml;
+use limit::Limits;
+
+pub const TOML_FILE: &'static str = "liongbot.toml";
+pub const JSON_FILE: &'static str = "liongbot.json";
+pub const ENV_FILE: &'static str = ".env";
+
+/// Parser of a config file format.
+type Parser = fn(&str) -> Result<Config, Error>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub enabled: bool,
+    /// Priority overriding the one the backend is registered with.
+    pub priority: Option<i32>,
+    /// Rate limits of the backend's own, instead of the shared ones.
+    pub limits: Option<Limits>,
+    /// Whether anonymous group members can use the backend, overriding the
+    /// backend's own choice.
+    pub anonymous: Option<bool>,
+    /// Backend-specific settings, given to `Backend::configure`.
+    pub settings: Value,
+}
+impl Default for BackendConfig {
+    fn default() -> BackendConfig {
+        BackendConfig {
+            enabled: true,
+            priority: None,
+            limits: None,
+            anonymous: None,
+            settings: Value::Null,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path to the SQLite database keeping history and backend states,
+    /// relative to the app directory.
+    pub database: Option<PathBuf>,
+    /// Bot owners, who can do anything.
+    pub owners: Vec<i64>,
+    /// Bot admins. More can be assigned by owners in chat.
+    pub admins: Vec<i64>,
+    /// Rate limits shared by backends without limits of their own.
+    pub limits: Limits,
+    /// Backend configs by backend identity. Backends not listed are enabled
+    /// with their registered priority and no settings.
+    pub backends: BTreeMap<String, BackendConfig>,
+}
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            database: Some(PathBuf::from("liongbot.db")),
+            owners: Vec::new(),
+            admins: Vec::new(),
+            limits: Limits::default(),
+            backends: BTreeMap::new(),
+        }
+    }
+}
+impl Config {
+    pub fn from_toml(raw: &str) -> Result<Config, Error> {
+        Ok(toml::from_str(raw)?)
+    }
+    pub fn from_json(raw: &str) -> Result<Config, Error> {
+        Ok(serde_json::from_str(raw)?)
+    }
+    /// Files in `dir` that config can be loaded from, existing or not.
+    pub fn paths(dir: &Path) -> Vec<PathBuf> {
+        vec![dir.join(TOML_FILE), dir.join(JSON_FILE), dir.join(ENV_FILE)]
+    }
+    /// Load config in `dir`, with overrides from environment variables. The
+    /// default config is used if there is no config file.
+    pub fn load(dir: &Path) -> Result<Config, Error> {
+        let mut config = Config::load_file(dir)?;
+        let mut vars = Vec::new();
+        let env_path = dir.join(ENV_FILE);
+        match dotenv::from_path_iter(&env_path) {
+            Ok(iter) => {
+                for var in iter {
+                    vars.push(var.map_err(|err| {
+                        err_msg(format!("invalid {}: {}",
+                                        env_path.display(), err))
+                    })?);
+                }
+            },
+            Err(ref err) if err.not_found() => {},
+            Err(err) => return Err(err.into()),
+        }
+        // Variables actually set in the environment take precedence.
+        vars.extend(env::vars());
+        config.apply_env(vars)?;
+        if let Some(ref mut db) = config.database {
+            *db = dir.join(&db);
+        }
+        Ok(config)
+    }
+    fn load_file(dir: &Path) -> Result<Config, Error> {
+        let toml_path = dir.join(TOML_FILE);
+        let json_path = dir.join(JSON_FILE);
+        let (path, parse): (_, Parser) =
+            if toml_path.exists() {
+                (toml_path, Config::from_toml)
+            } else if json_path.exists() {
+                (json_path, Config::from_json)
+            } else {
+                return Ok(Config::default())
+            };
+        let mut raw = String::new();
+        File::open(&path)?.read_to_string(&mut raw)?;
+        parse(&raw).map_err(|err| {
+            err_msg(format!("invalid {}: {}", path.display(), err))
+        })
+    }
+    /// Apply overrides in environment variables. Variables without the
+    /// `LIONGBOT_` prefix are ignored.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), Error>
+            where I: IntoIterator<Item=(String, String)> {
+        for (key, value) in vars {
+            match key.as_str() {
+                "LIONGBOT_DATABASE" if value.is_empty() => self.database = None,
+                "LIONGBOT_DATABASE" => self.database = Some(value.into()),
+                "LIONGBOT_ENABLE" | "LIONGBOT_DISABLE" => {
+                    let enabled = key == "LIONGBOT_ENABLE";
+                    for identity in value.split(',') {
+                        let identity = identity.trim();
+                        if identity.is_empty() { continue }
+                        self.backends.entry(identity.to_owned())
+                            .or_default()
+                            .enabled = enabled;
+                    }
+                },
+                x if x.starts_with("LIONGBOT_") => {
+                    return Err(err_msg(format!("unknown variable `{}`", x)))
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize backend settings. Missing settings give the default.
+pub fn parse_settings<T>(settings: &Value) -> Result<T, Error>
+        where T: DeserializeOwned + Default {
+    if settings.is_null() {
+        Ok(T::default())
+    } else {
+        Ok(serde_json::from_value(settings.clone())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let from_toml = Config::from_toml(r#"
+            database = "a.db"
+            admins = [1]
+            [backends."test.a"]
+            priority = 10
+            settings = { x = 1 }
+            [backends."test.b"]
+            enabled = false
+        "#).unwrap();
+        let from_json = Config::from_json(r#"{
+            "database": "a.db",
+            "admins": [1],
+            "backends": {
+                "test.a": { "priority": 10, "settings": { "x": 1 } },
+                "test.b": { "enabled": false }
+            }
+        }"#).unwrap();
+        assert_eq!(from_toml, from_json);
+        let a = &from_toml.backends["test.a"];
+        assert!(a.enabled);
+        assert_eq!(a.priority, Some(10));
+        assert_eq!(a.settings["x"], 1);
+        assert!(!from_toml.backends["test.b"].enabled);
+
+        let err = Config::from_toml("[backends.\"test.a\"]\nprority = 1")
+            .unwrap_err();
+        assert!(err.to_string().contains("prority"));
+    }
+    #[test]
+    fn test_env() {
+        let mut config = Config::default();
+        let vars = vec![
+            ("PATH", "/bin"),
+            ("LIONGBOT_DATABASE", "b.db"),
+            ("LIONGBOT_DISABLE", "test.a, test.b"),
+            ("LIONGBOT_ENABLE", "test.b"),
+        ];
+        config.apply_env(vars.into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))).unwrap();
+        assert_eq!(config.database, Some(PathBuf::from("b.db")));
+        assert!(!config.backends["test.a"].enabled);
+        assert!(config.backends["test.b"].enabled);
+
+        let typo = vec![("LIONGBOT_DATABSE".to_owned(), String::new())];
+        assert!(config.apply_env(typo).is_err());
+    }
+}
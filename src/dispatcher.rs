@@ -1,20 +1,98 @@
 //! Dispatcher for routing of all message backends.
-use std::cell::Cell;
-use std::os::raw::c_char;
-use failure::Error;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime};
+use failure::{err_msg, Error};
 use {Backend, Composer, Msg, MsgIn};
+use backend::{BackendMetadata, Context, Outcome};
+use config::Config;
+use history::History;
+use limit::{self, Limits, RateLimiter, Verdict};
+use middleware::Middleware;
+use peripheral::{Event, Lifecycle, Peripheral};
+use role::{self, Role, Roles};
+use storage::{MemoryStorage, Storage};
+use unwind;
+
+/// What to do with incoming messages while the dispatcher is disabled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisabledPolicy {
+    /// Drop incoming messages.
+    Drop,
+    /// Keep at most the given number of the latest incoming messages, and
+    /// dispatch them when the dispatcher is enabled again.
+    Queue(usize),
+}
+
+struct BackendEntry {
+    priority: i32,
+    enabled: Cell<bool>,
+    /// Limiter of the backend's own, if it's not limited by the dispatcher's.
+    limiter: Option<RateLimiter>,
+    /// Whether anonymous group members can use the backend.
+    anonymous: bool,
+    backend: Box<Backend>,
+}
+
+/// Rebuild backends on reload. A blank dispatcher is given to register
+/// backends and apply config on, and only its backends are taken.
+type Reloader = Box<Fn(&mut Dispatcher) -> Result<(), Error>>;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
 
 pub struct Dispatcher {
     composer: Box<Composer>,
     enabled: Cell<bool>,
-    backends: Vec<Box<Backend>>,
+    disabled_policy: DisabledPolicy,
+    queue: RefCell<VecDeque<MsgIn>>,
+    history: Option<History>,
+    limiter: RateLimiter,
+    middlewares: Vec<Box<Middleware>>,
+    roles: Roles,
+    storage: Box<Storage>,
+    /// Backends sorted by descending priority. Backends of the same priority
+    /// are kept in registration order. Dispatching holds a snapshot of them,
+    /// so that a reload doesn't affect messages being handled.
+    backends: RefCell<Rc<Vec<BackendEntry>>>,
+    /// Backends enabled or disabled at runtime, which stay so on reload.
+    toggled: RefCell<BTreeMap<String, bool>>,
+    reloader: Option<Reloader>,
+    /// Settings changed in config which only take effect after a restart.
+    /// Reloaders note them on the blank dispatchers they are given.
+    restart_needed: Vec<&'static str>,
+    /// Files that trigger a reload on change, with their last modified time.
+    watched: Vec<(PathBuf, Cell<Option<SystemTime>>)>,
+}
+impl Default for Dispatcher {
+    fn default() -> Dispatcher {
+        Dispatcher::new()
+    }
 }
 impl Dispatcher {
     pub fn new() -> Dispatcher {
         Dispatcher {
             composer: Box::new(DefaultComposer()),
             enabled: Cell::new(false),
-            backends: Vec::new(),
+            disabled_policy: DisabledPolicy::Drop,
+            queue: RefCell::new(VecDeque::new()),
+            history: None,
+            limiter: RateLimiter::new(Limits::default()),
+            middlewares: Vec::new(),
+            roles: Roles::new(),
+            storage: Box::new(MemoryStorage::new()),
+            backends: RefCell::new(Rc::new(Vec::new())),
+            toggled: RefCell::new(BTreeMap::new()),
+            reloader: None,
+            restart_needed: Vec::new(),
+            watched: Vec::new(),
         }
     }
 
@@ -30,32 +108,459 @@ impl Dispatcher {
     pub fn disable(&self) {
         self.enabled.set(false);
     }
+    /// Take all the messages queued while the dispatcher was disabled, in the
+    /// order they were received.
+    fn take_queued(&self) -> Vec<MsgIn> {
+        self.queue.borrow_mut().drain(..).collect()
+    }
+
+    fn snapshot(&self) -> Rc<Vec<BackendEntry>> {
+        self.backends.borrow().clone()
+    }
+    fn backends_mut(&mut self) -> &mut Vec<BackendEntry> {
+        // Snapshots only live during `&self` calls, so there is none now.
+        Rc::get_mut(self.backends.get_mut())
+            .expect("backends are modified while being dispatched to")
+    }
+    fn with_backend<F, T>(&self, identity: &str, f: F) -> Option<T>
+            where F: FnOnce(&BackendEntry) -> T {
+        self.snapshot().iter()
+            .find(|entry| entry.backend.metadata().identity == identity)
+            .map(f)
+    }
+    /// Check whether the backend of the given identity is enabled. `None` is
+    /// returned if there is no such backend.
+    pub fn is_backend_enabled(&self, identity: &str) -> Option<bool> {
+        self.with_backend(identity, |entry| entry.enabled.get())
+    }
+    fn toggle_backend(&self, identity: &str, enabled: bool) -> bool {
+        let found = self.with_backend(identity, |entry| {
+            entry.enabled.set(enabled)
+        });
+        if found.is_some() {
+            self.toggled.borrow_mut().insert(identity.to_owned(), enabled);
+        }
+        found.is_some()
+    }
+    /// Enable the backend of the given identity. Returns `false` if there is
+    /// no such backend. It stays enabled on reload, whatever config says.
+    pub fn enable_backend(&self, identity: &str) -> bool {
+        self.toggle_backend(identity, true)
+    }
+    /// Disable the backend of the given identity, so that it won't see any
+    /// message until enabled again, even on reload. Returns `false` if there
+    /// is no such backend.
+    pub fn disable_backend(&self, identity: &str) -> bool {
+        self.toggle_backend(identity, false)
+    }
 
     pub fn composer(&self) -> &Composer {
         &*self.composer
     }
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+    pub fn context<'a>(&'a self, peripheral: &'a Peripheral) -> Context<'a> {
+        let ctx = Context::new(peripheral, self.composer())
+            .with_dispatcher(self)
+            .with_limiter(&self.limiter)
+            .with_middlewares(&self.middlewares)
+            .with_roles(&self.roles)
+            .with_storage(&*self.storage);
+        match self.history {
+            Some(ref history) => ctx.with_history(history),
+            None => ctx,
+        }
+    }
 
     pub fn use_composer<C>(&mut self, composer: C) -> &mut Dispatcher
             where C: 'static + Composer {
         self.composer = Box::new(composer);
         self
     }
+    /// Append a middleware to the chain. Incoming messages pass through
+    /// middlewares in registration order, and outgoing messages in reverse
+    /// order.
+    pub fn use_middleware<M>(&mut self, middleware: M) -> &mut Dispatcher
+            where M: 'static + Middleware {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+    pub fn use_disabled_policy(&mut self, policy: DisabledPolicy)
+            -> &mut Dispatcher {
+        self.disabled_policy = policy;
+        self
+    }
+    /// Keep backend states in `storage`. States are kept in memory by
+    /// default.
+    pub fn use_storage<S>(&mut self, storage: S) -> &mut Dispatcher
+            where S: 'static + Storage {
+        self.storage = Box::new(storage);
+        self
+    }
+    /// Limit the rate of messages reaching backends, and of messages sent.
+    /// Backends without limits of their own share the limits. There is no
+    /// limit by default.
+    pub fn use_limits(&mut self, limits: Limits) -> &mut Dispatcher {
+        self.limiter = RateLimiter::new(limits);
+        self
+    }
+    /// Give users roles from config.
+    pub fn use_roles(&mut self, roles: Roles) -> &mut Dispatcher {
+        self.roles = roles;
+        self
+    }
+    /// Record all incoming and outgoing messages in `history`.
+    pub fn use_history(&mut self, history: History) -> &mut Dispatcher {
+        self.history = Some(history);
+        self
+    }
+    /// Register a backend. Backends of higher priority are previewed first.
     pub fn use_backend<B>(&mut self, backend: B, priority: i32)
             -> &mut Dispatcher where B: 'static + Backend {
-        self.backends.push(Box::new(backend));
+        {
+            let backends = self.backends_mut();
+            // Insert after all the backends of the same or higher priority,
+            // so that registration order breaks ties.
+            let pos = backends.iter()
+                .position(|entry| entry.priority < priority)
+                .unwrap_or(backends.len());
+            backends.insert(pos, BackendEntry {
+                priority: priority,
+                enabled: Cell::new(true),
+                limiter: None,
+                anonymous: backend.accepts_anonymous(),
+                backend: Box::new(backend),
+            });
+        }
         self
     }
-    pub fn dispatch(&self, msg_in: MsgIn) -> Option<Msg> {
-        let is_priv = msg_in.is_priv();
-        for backend in self.backends.iter() {
-            if backend.preview(&msg_in) {
-                match backend.process(&msg_in) {
-                    Ok(msg) => return Some(msg),
-                    _ => continue,
+    /// Make the dispatcher reloadable. On reload, `reloader` is called with a
+    /// blank dispatcher, on which backends should be registered and config
+    /// should be applied, as in the initial configuration. Only the backends
+    /// are taken from it; the composer, roles, limits, storage and history
+    /// are kept. Reloaders should note changes to those in config with
+    /// `note_restart_needed`.
+    pub fn use_reloader<F>(&mut self, reloader: F) -> &mut Dispatcher
+            where F: 'static + Fn(&mut Dispatcher) -> Result<(), Error> {
+        self.reloader = Some(Box::new(reloader));
+        self
+    }
+    /// Reload when the file at `path` is changed, created or removed. Changes
+    /// are checked in `poll_reload`.
+    pub fn use_watched_file<P>(&mut self, path: P) -> &mut Dispatcher
+            where P: AsRef<Path> {
+        let path = path.as_ref().to_owned();
+        let mtime = modified(&path);
+        self.watched.push((path, Cell::new(mtime)));
+        self
+    }
+    /// Note that `setting` has changed in config, but only takes effect after
+    /// a restart.
+    pub fn note_restart_needed(&mut self, setting: &'static str)
+            -> &mut Dispatcher {
+        self.restart_needed.push(setting);
+        self
+    }
+    /// Enable, prioritize and configure registered backends as specified in
+    /// `config`. All the problems found are reported together in the error.
+    pub fn apply_config(&mut self, config: &Config) -> Result<(), Error> {
+        let mut errs = Vec::new();
+        let backends = self.backends_mut();
+        for identity in config.backends.keys() {
+            let known = backends.iter()
+                .any(|entry| entry.backend.metadata().identity == identity);
+            if !known {
+                errs.push(format!("unknown backend `{}`", identity));
+            }
+        }
+        for entry in backends.iter_mut() {
+            let identity = entry.backend.metadata().identity;
+            let backend_config = match config.backends.get(identity) {
+                Some(x) => x,
+                None => continue,
+            };
+            entry.enabled.set(backend_config.enabled);
+            if let Some(priority) = backend_config.priority {
+                entry.priority = priority;
+            }
+            entry.limiter = backend_config.limits.map(RateLimiter::new);
+            if let Some(anonymous) = backend_config.anonymous {
+                entry.anonymous = anonymous;
+            }
+            let settings = &backend_config.settings;
+            if let Err(err) = entry.backend.configure(settings) {
+                errs.push(format!("invalid settings for backend `{}`: {}",
+                                  identity, err));
+            }
+        }
+        // Stable, so that registration order still breaks ties.
+        backends.sort_by_key(|x| ::std::cmp::Reverse(x.priority));
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(err_msg(errs.join("\n")))
+        }
+    }
+    /// Rebuild all backends with the reloader. The new backends replace the
+    /// old ones at once, after the old ones are shut down and before the new
+    /// ones are launched. Backends enabled or disabled at runtime are kept
+    /// so. If the reloader fails, the old backends are kept untouched.
+    ///
+    /// Settings noted by the reloader to need a restart are returned.
+    pub fn reload(&self, peripheral: &Peripheral)
+            -> Result<Vec<&'static str>, Error> {
+        let reloader = self.reloader.as_ref()
+            .ok_or_else(|| err_msg("reloading is not supported"))?;
+        let mut fresh = Dispatcher::new();
+        reloader(&mut fresh)?;
+        for (identity, &enabled) in self.toggled.borrow().iter() {
+            fresh.with_backend(identity, |entry| entry.enabled.set(enabled));
+        }
+        if self.is_enabled() {
+            self.notify(peripheral, Lifecycle::Disable);
+        }
+        self.notify(peripheral, Lifecycle::Shutdown);
+        self.backends.replace(fresh.backends.into_inner());
+        self.notify(peripheral, Lifecycle::Launch);
+        if self.is_enabled() {
+            self.notify(peripheral, Lifecycle::Enable);
+        }
+        Ok(fresh.restart_needed)
+    }
+    /// Reload if any watched file has changed since the last check. Returns
+    /// whether a reload happened. Peripherals should call this from time to
+    /// time, e.g. before handling each message.
+    pub fn poll_reload(&self, peripheral: &Peripheral) -> Result<bool, Error> {
+        let mut changed = false;
+        for (path, last) in self.watched.iter() {
+            let mtime = modified(path);
+            if mtime != last.get() {
+                // Remember the change even if the reload fails, so that it's
+                // not retried until the file is changed again.
+                last.set(mtime);
+                changed = true;
+            }
+        }
+        if changed {
+            let stale = self.reload(peripheral)?;
+            if !stale.is_empty() {
+                warn!("restart to apply changes to {}", stale.join(", "));
+            }
+        }
+        Ok(changed)
+    }
+    /// Metadata of all registered backends in the order they are previewed,
+    /// with the priority they are registered with.
+    pub fn backends(&self) -> Vec<BackendMetadata> {
+        self.snapshot().iter()
+            .map(|entry| BackendMetadata {
+                priority: entry.priority,
+                ..entry.backend.metadata()
+            })
+            .collect()
+    }
+    /// Route the incoming message through backends by priority, until one of
+    /// them consumes it. `Outcome::Pass` is returned if no backend is
+    /// interested in the message.
+    ///
+    /// Messages received while the dispatcher is disabled are handled as
+    /// specified by the `DisabledPolicy`. Messages from blacklisted users are
+    /// ignored. Users exceeding rate limits are told to slow down once, and
+    /// are then ignored until they are allowed again. Users without the role
+    /// a backend requires are refused, if they are within the limits.
+    ///
+    /// Middlewares see the message before backends do, and can rewrite it or
+    /// give the outcome themselves.
+    pub fn dispatch(&self, peripheral: &Peripheral, msg_in: &MsgIn)
+            -> Outcome {
+        if let Some(ref history) = self.history {
+            // Don't let a broken database stop the bot from working.
+            if let Err(err) = history.record_in(msg_in) {
+                warn!("unable to record incoming message: {}", err);
+            }
+        }
+        self.dispatch_recorded(peripheral, msg_in)
+    }
+    /// Dispatch a message that has already been recorded in history.
+    fn dispatch_recorded(&self, peripheral: &Peripheral, msg_in: &MsgIn)
+            -> Outcome {
+        if self.is_disabled() {
+            if let DisabledPolicy::Queue(cap) = self.disabled_policy {
+                let mut queue = self.queue.borrow_mut();
+                if cap > 0 && queue.len() >= cap {
+                    queue.pop_front();
+                }
+                if cap > 0 {
+                    queue.push_back(msg_in.clone());
+                }
+            }
+            return Outcome::Pass
+        }
+        let ctx = self.context(peripheral);
+        // A broken database shouldn't block everyone.
+        match ctx.has_role(msg_in, Role::Member) {
+            Ok(true) => {},
+            Ok(false) => return Outcome::Pass,
+            Err(err) => warn!("unable to check role of {}: {}",
+                              msg_in.qq(), err),
+        }
+        let mut msg_in = msg_in.clone();
+        for middleware in self.middlewares.iter() {
+            let ctx = ctx.clone().with_owner(middleware.name());
+            let rv = unwind::catch(middleware.name(), || {
+                middleware.before(&ctx, &mut msg_in)
+            });
+            match rv {
+                Ok(Ok(Some(outcome))) => return outcome,
+                Ok(Ok(None)) => {},
+                Ok(Err(err)) => {
+                    warn!("middleware `{}` failed: {}", middleware.name(), err);
+                    return Outcome::Fail(unwind::apology())
+                },
+                Err(_) => return Outcome::Fail(unwind::apology()),
+            }
+        }
+        let msg_in = &msg_in;
+        // Backends might reload the dispatcher, but the message is still
+        // handled by those it started with.
+        let backends = self.snapshot();
+        // Limiters already passed. Each takes tokens once for a message,
+        // however many backends share it.
+        let mut passed: Vec<&RateLimiter> = Vec::new();
+        for entry in backends.iter() {
+            if !entry.enabled.get() || !entry.backend.preview(msg_in) {
+                continue
+            }
+            if msg_in.anon().is_some() && !entry.anonymous {
+                continue
+            }
+            // Refusals are replies too, so users without the role are limited
+            // as well.
+            let limiter = entry.limiter.as_ref().unwrap_or(&self.limiter);
+            if !passed.iter().any(|x| ptr::eq(*x, limiter)) {
+                match limiter.check(msg_in, Instant::now()) {
+                    Verdict::Allow => passed.push(limiter),
+                    Verdict::Notice => return Outcome::Fail(limit::notice()),
+                    Verdict::Deny => return Outcome::Handled,
+                }
+            }
+            let required = entry.backend.required_role();
+            if required > Role::Member {
+                match ctx.has_role(msg_in, required) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        return Outcome::Fail(role::refusal(required))
+                    },
+                    Err(err) => {
+                        warn!("unable to check role of {}: {}",
+                              msg_in.qq(), err);
+                        return Outcome::Fail(unwind::apology())
+                    },
+                }
+            }
+            let meta = entry.backend.metadata();
+            let ctx = ctx.clone().with_owner(meta.identity);
+            let rv = unwind::catch(meta.identity, || {
+                entry.backend.process(&ctx, msg_in)
+            });
+            let outcome = match rv {
+                Ok(Ok(outcome)) => outcome,
+                // Errors might tell too much about the bot, so users are
+                // only told what backends choose to tell with `Fail`.
+                Ok(Err(err)) => {
+                    warn!("backend `{}` failed: {}", meta.identity, err);
+                    Outcome::Fail(unwind::apology())
+                },
+                // A panicking backend is broken, whatever it has to say.
+                Err(_) => Outcome::Fail(unwind::apology()),
+            };
+            if outcome.is_consumed() {
+                return outcome
+            }
+        }
+        Outcome::Pass
+    }
+    /// Dispatch the incoming message and send all the replies back to where
+    /// it came from. An error is returned if the message is consumed but the
+    /// replies cannot be delivered.
+    pub fn handle(&self, peripheral: &Peripheral, msg_in: &MsgIn)
+            -> Result<Outcome, Error> {
+        let outcome = self.dispatch(peripheral, msg_in);
+        self.send_replies(peripheral, msg_in, outcome)
+    }
+    fn send_replies(&self, peripheral: &Peripheral, msg_in: &MsgIn,
+                    outcome: Outcome) -> Result<Outcome, Error> {
+        let ctx = self.context(peripheral);
+        for msg in outcome.clone().into_replies() {
+            ctx.reply(msg_in, &msg)?;
+        }
+        Ok(outcome)
+    }
+    fn notify(&self, peripheral: &Peripheral, event: Lifecycle) {
+        let ctx = self.context(peripheral);
+        for entry in self.snapshot().iter() {
+            let meta = entry.backend.metadata();
+            let ctx = ctx.clone().with_owner(meta.identity);
+            // Other backends should still be notified.
+            let _ = unwind::catch(meta.identity, || {
+                entry.backend.on_lifecycle(&ctx, event)
+            });
+        }
+    }
+    /// Deliver an event to backends until one of them handles it. Events are
+    /// dropped while the dispatcher is disabled. Whether the event is
+    /// handled is returned.
+    pub fn on_event(&self, peripheral: &Peripheral, event: &Event) -> bool {
+        if !self.is_enabled() {
+            return false
+        }
+        let ctx = self.context(peripheral);
+        for entry in self.snapshot().iter() {
+            if !entry.enabled.get() {
+                continue
+            }
+            let meta = entry.backend.metadata();
+            let ctx = ctx.clone().with_owner(meta.identity);
+            let rv = unwind::catch(meta.identity, || {
+                entry.backend.on_event(&ctx, event)
+            });
+            match rv {
+                Ok(Ok(true)) => return true,
+                Ok(Ok(false)) | Err(_) => {},
+                Ok(Err(err)) => {
+                    warn!("backend `{}` failed on {:?}: {}", meta.identity,
+                          event, err);
+                },
+            }
+        }
+        false
+    }
+    /// Notify all backends of a lifecycle event. On `Lifecycle::Enable`,
+    /// messages queued while the dispatcher was disabled are handled. Replies
+    /// failing to be sent are logged, and don't keep the rest from being
+    /// handled.
+    pub fn on_lifecycle(&self, peripheral: &Peripheral, event: Lifecycle)
+            -> Result<(), Error> {
+        match event {
+            Lifecycle::Enable => self.enable(),
+            Lifecycle::Disable => self.disable(),
+            _ => {},
+        }
+        self.notify(peripheral, event);
+        if event == Lifecycle::Enable {
+            // Catch up with messages received while we were disabled. They
+            // have been recorded on arrival.
+            for msg_in in self.take_queued() {
+                let outcome = self.dispatch_recorded(peripheral, &msg_in);
+                if let Err(err) = self.send_replies(peripheral, &msg_in,
+                                                    outcome) {
+                    warn!("unable to reply to queued message from {}: {}",
+                          msg_in.qq(), err);
                 }
             }
         }
-        None
+        Ok(())
     }
 }
 
@@ -82,3 +587,548 @@ impl Composer for DefaultComposer {
         Ok(::msg::text(raw))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::err_msg;
+    use peripheral::{MemberInfo, UserInfo};
+    use peripheral::memory::{MemoryPeripheral, Sent};
+
+    use config::parse_settings;
+    use msg::Anonymous;
+    use limit::Rate;
+    use serde_json::Value;
+    use storage::Scope;
+
+    struct Echo(&'static str);
+    impl Backend for Echo {
+        fn metadata(&self) -> BackendMetadata {
+            BackendMetadata {
+                identity: self.0,
+                ..Default::default()
+            }
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, _: &Context, _: &MsgIn) -> Result<Outcome, Error> {
+            match self.0 {
+                "" => Ok(Outcome::Pass),
+                "!" => Err(err_msg("oops")),
+                "panic" => panic!("boom"),
+                x => Ok(Outcome::Reply(::msg::text(x))),
+            }
+        }
+    }
+    fn reply(text: &str) -> Outcome {
+        Outcome::Reply(::msg::text(text))
+    }
+    fn make_msg_in() -> MsgIn {
+        MsgIn::Private {
+            qq: 1,
+            alias: "1".to_owned(),
+            content: ::msg::text("hello"),
+        }
+    }
+    #[test]
+    fn test_priority_order() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("a"), 0)
+            .use_backend(Echo("b"), 10)
+            .use_backend(Echo("c"), 0)
+            .use_backend(Echo("d"), -10)
+            .use_backend(Echo("e"), 10);
+        let order = dispatcher.backends().iter()
+            .map(|meta| (meta.identity, meta.priority))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![
+            ("b", 10), ("e", 10), ("a", 0), ("c", 0), ("d", -10),
+        ]);
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("b"));
+    }
+    #[test]
+    fn test_fallthrough() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo(""), 10)
+            .use_backend(Echo("fallback"), 0);
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()),
+                   reply("fallback"));
+    }
+    #[test]
+    fn test_panic() {
+        ::logger::init_test();
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("panic"), 10)
+            .use_backend(Echo("fallback"), 0);
+        let outcome = dispatcher.handle(&peri, &make_msg_in()).unwrap();
+        assert_eq!(outcome, Outcome::Fail(unwind::apology()));
+        assert_eq!(peri.take_sent(), vec![
+            Sent::Private(1, "Oops, something went wrong.".to_owned()),
+        ]);
+        // The dispatcher is still usable.
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), outcome);
+    }
+    #[test]
+    fn test_disabled_policy() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.use_backend(Echo("a"), 0);
+        let msg_in = make_msg_in();
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in), Outcome::Pass);
+        assert!(dispatcher.take_queued().is_empty());
+
+        dispatcher.use_disabled_policy(DisabledPolicy::Queue(2));
+        for _ in 0..3 {
+            assert_eq!(dispatcher.dispatch(&peri, &msg_in), Outcome::Pass);
+        }
+        assert_eq!(dispatcher.take_queued().len(), 2);
+        assert!(dispatcher.take_queued().is_empty());
+
+        dispatcher.enable();
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in), reply("a"));
+    }
+    #[test]
+    fn test_handle() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_disabled_policy(DisabledPolicy::Queue(2))
+            .use_backend(Echo("a"), 0);
+        let grp_msg_in = MsgIn::Group {
+            grp: 2,
+            qq: 1,
+            alias: "1".to_owned(),
+            grp_alias: "1".to_owned(),
+            content: ::msg::text("hello"),
+            anon: None,
+        };
+        dispatcher.handle(&peri, &make_msg_in()).unwrap();
+        assert!(peri.take_sent().is_empty());
+        dispatcher.on_lifecycle(&peri, Lifecycle::Enable).unwrap();
+        assert_eq!(peri.take_sent(), vec![Sent::Private(1, "a".to_owned())]);
+        assert_eq!(dispatcher.handle(&peri, &grp_msg_in).unwrap(), reply("a"));
+        assert_eq!(peri.take_sent(), vec![Sent::Group(2, "a".to_owned())]);
+    }
+    /// Peripheral unable to send anything to user 1.
+    struct Picky(MemoryPeripheral);
+    impl Peripheral for Picky {
+        fn name(&self) -> &'static str {
+            "peripheral.picky"
+        }
+        fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error> {
+            if qq == 1 {
+                return Err(err_msg("user 1 is unreachable"))
+            }
+            self.0.send_priv(qq, raw)
+        }
+        fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error> {
+            self.0.send_grp(grp, raw)
+        }
+        fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error> {
+            self.0.send_discuss(discuss, raw)
+        }
+        fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+            self.0.user_info(qq)
+        }
+        fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+            self.0.member_info(grp, qq)
+        }
+    }
+    #[test]
+    fn test_queue_failure() {
+        ::logger::init_test();
+        let peri = Picky(MemoryPeripheral::new());
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_disabled_policy(DisabledPolicy::Queue(2))
+            .use_backend(Echo("a"), 0);
+        let msg_in = |qq: i64| MsgIn::Private {
+            qq: qq,
+            alias: qq.to_string(),
+            content: ::msg::text("hello"),
+        };
+        dispatcher.handle(&peri, &msg_in(1)).unwrap();
+        dispatcher.handle(&peri, &msg_in(3)).unwrap();
+        dispatcher.on_lifecycle(&peri, Lifecycle::Enable).unwrap();
+        assert_eq!(peri.0.take_sent(), vec![Sent::Private(3, "a".to_owned())]);
+    }
+    #[test]
+    fn test_backend_switch() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("a"), 10)
+            .use_backend(Echo("b"), 0);
+        assert!(dispatcher.disable_backend("a"));
+        assert_eq!(dispatcher.is_backend_enabled("a"), Some(false));
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("b"));
+        assert!(dispatcher.enable_backend("a"));
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("a"));
+        assert!(!dispatcher.disable_backend("c"));
+        assert_eq!(dispatcher.is_backend_enabled("c"), None);
+    }
+    #[test]
+    fn test_error_as_apology() {
+        ::logger::init_test();
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("!"), 10)
+            .use_backend(Echo("a"), 0);
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()),
+                   Outcome::Fail(unwind::apology()));
+    }
+    /// Count messages from each user.
+    struct Counter(&'static str);
+    impl Backend for Counter {
+        fn metadata(&self) -> BackendMetadata {
+            BackendMetadata {
+                identity: self.0,
+                ..Default::default()
+            }
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, ctx: &Context, msg_in: &MsgIn)
+                -> Result<Outcome, Error> {
+            let scope = Scope::User(msg_in.qq());
+            let n = ctx.store().get_json::<u32>(scope, "n")?.unwrap_or(0);
+            ctx.store().set_json(scope, "n", &(n + 1))?;
+            Ok(Outcome::Pass)
+        }
+    }
+    #[derive(Default, Deserialize)]
+    struct Greeting {
+        greeting: String,
+    }
+    /// Reply with the greeting in settings.
+    struct Greeter(String);
+    impl Backend for Greeter {
+        fn metadata(&self) -> BackendMetadata {
+            BackendMetadata {
+                identity: "greeter",
+                ..Default::default()
+            }
+        }
+        fn configure(&mut self, settings: &Value) -> Result<(), Error> {
+            self.0 = parse_settings::<Greeting>(settings)?.greeting;
+            Ok(())
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, _: &Context, _: &MsgIn) -> Result<Outcome, Error> {
+            Ok(Outcome::Reply(::msg::text(&self.0)))
+        }
+        fn on_event(&self, ctx: &Context, event: &Event)
+                -> Result<bool, Error> {
+            match *event {
+                Event::MemberJoin { grp, .. } => {
+                    ctx.send_grp(grp, &::msg::text(&self.0))?;
+                    Ok(true)
+                },
+                _ => Ok(false),
+            }
+        }
+    }
+    #[test]
+    fn test_event() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_backend(Echo("a"), 10)
+            .use_backend(Greeter("welcome".to_owned()), 0);
+        let join = Event::MemberJoin {
+            grp: 2,
+            qq: 1,
+            operator: 3,
+            invited: false,
+        };
+        assert!(!dispatcher.on_event(&peri, &join));
+        dispatcher.enable();
+        assert!(dispatcher.on_event(&peri, &join));
+        assert!(!dispatcher.on_event(&peri, &Event::FriendAdded { qq: 1 }));
+        assert_eq!(peri.take_sent(), vec![
+            Sent::Group(2, "welcome".to_owned()),
+        ]);
+    }
+    #[test]
+    fn test_apply_config() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("a"), 10)
+            .use_backend(Greeter(String::new()), 0)
+            .use_backend(Echo("b"), 0);
+        let config = Config::from_toml(r#"
+            [backends.a]
+            enabled = false
+            [backends.b]
+            priority = 20
+            [backends.greeter]
+            priority = 20
+            settings = { greeting = "hi" }
+        "#).unwrap();
+        dispatcher.apply_config(&config).unwrap();
+        let order = dispatcher.backends().iter()
+            .map(|meta| (meta.identity, meta.priority))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![("greeter", 20), ("b", 20), ("a", 10)]);
+        assert_eq!(dispatcher.is_backend_enabled("a"), Some(false));
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("hi"));
+
+        let config = Config::from_json(r#"{ "backends": {
+            "c": {},
+            "greeter": { "settings": { "greeting": 1 } }
+        } }"#).unwrap();
+        let err = dispatcher.apply_config(&config).unwrap_err().to_string();
+        let lines = err.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "unknown backend `c`");
+        assert!(lines[1].starts_with("invalid settings for backend `greeter`"));
+    }
+    #[test]
+    fn test_anonymous() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("a"), 10)
+            .use_backend(Echo("b"), 0);
+        let config = Config::from_toml(r#"
+            [backends.b]
+            anonymous = true
+        "#).unwrap();
+        dispatcher.apply_config(&config).unwrap();
+        let make_grp_msg_in = |anon| MsgIn::Group {
+            grp: 2,
+            qq: 80000000,
+            alias: String::new(),
+            grp_alias: String::new(),
+            content: ::msg::text("hello"),
+            anon: anon,
+        };
+        assert_eq!(dispatcher.dispatch(&peri, &make_grp_msg_in(None)),
+                   reply("a"));
+        let anon = Anonymous {
+            id: 1,
+            name: "Anonymous Penguin".to_owned(),
+            token: "token".to_owned(),
+        };
+        assert_eq!(dispatcher.dispatch(&peri, &make_grp_msg_in(Some(anon))),
+                   reply("b"));
+    }
+    /// Echo only for bot admins.
+    struct AdminEcho;
+    impl Backend for AdminEcho {
+        fn metadata(&self) -> BackendMetadata {
+            Default::default()
+        }
+        fn required_role(&self) -> Role {
+            Role::BotAdmin
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, _: &Context, _: &MsgIn) -> Result<Outcome, Error> {
+            Ok(reply("admin"))
+        }
+    }
+    #[test]
+    fn test_required_role() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_roles(Roles::new().with_admin(1))
+            .use_backend(AdminEcho, 0);
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("admin"));
+        dispatcher.use_roles(Roles::new());
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()),
+                   Outcome::Fail(role::refusal(Role::BotAdmin)));
+    }
+    #[test]
+    fn test_rate_limit() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        let once = Rate { burst: 1, per_minute: 1 };
+        dispatcher
+            .use_limits(Limits { user: Some(once), ..Default::default() })
+            .use_backend(Echo("a"), 10)
+            .use_backend(Echo("b"), 0);
+        let config = Config::from_toml(r#"
+            [backends.b.limits]
+            global = { burst = 2, per_minute = 1 }
+        "#).unwrap();
+        dispatcher.apply_config(&config).unwrap();
+        let msg_in = make_msg_in();
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in), reply("a"));
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in),
+                   Outcome::Fail(limit::notice()));
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in), Outcome::Handled);
+        dispatcher.disable_backend("a");
+        assert_eq!(dispatcher.dispatch(&peri, &msg_in), reply("b"));
+    }
+    #[test]
+    fn test_rate_limit_shared() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        let once = Rate { burst: 1, per_minute: 1 };
+        dispatcher
+            .use_limits(Limits {
+                user: Some(once),
+                global: Some(once),
+                ..Default::default()
+            })
+            .use_backend(Echo(""), 10)
+            .use_backend(Echo("a"), 0);
+        // A backend passing the message doesn't use up the tokens for the
+        // next.
+        let outcome = dispatcher.handle(&peri, &make_msg_in()).unwrap();
+        assert_eq!(outcome, reply("a"));
+        assert_eq!(peri.take_sent().len(), 1);
+        // The next message is stopped by the user bucket, and nothing can be
+        // sent until the global bucket is refilled.
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()),
+                   Outcome::Fail(limit::notice()));
+        let ctx = dispatcher.context(&peri);
+        assert!(ctx.send_priv(1, &::msg::text("hi")).is_err());
+        assert!(peri.take_sent().is_empty());
+    }
+    #[test]
+    fn test_rate_limit_refusal() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        let twice = Rate { burst: 2, per_minute: 1 };
+        dispatcher
+            .use_limits(Limits {
+                user: Some(twice),
+                global: Some(twice),
+                ..Default::default()
+            })
+            .use_backend(AdminEcho, 0);
+        let refusal = Outcome::Fail(role::refusal(Role::BotAdmin));
+        for _ in 0..2 {
+            let outcome = dispatcher.handle(&peri, &make_msg_in()).unwrap();
+            assert_eq!(outcome, refusal);
+        }
+        // Told to slow down, but nothing more can be sent.
+        assert!(dispatcher.handle(&peri, &make_msg_in()).is_err());
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()),
+                   Outcome::Handled);
+        assert_eq!(peri.take_sent().len(), 2);
+    }
+    #[test]
+    fn test_poll_reload() {
+        let peri = MemoryPeripheral::new();
+        let path = ::std::env::temp_dir().join("liongbot-test-watch.toml");
+        let _ = fs::remove_file(&path);
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Echo("a"), 0)
+            .use_watched_file(&path)
+            .use_reloader(|dispatcher| {
+                dispatcher.use_backend(Echo("b"), 0);
+                Ok(())
+            });
+        assert!(!dispatcher.poll_reload(&peri).unwrap());
+        fs::write(&path, "").unwrap();
+        assert!(dispatcher.poll_reload(&peri).unwrap());
+        assert!(!dispatcher.poll_reload(&peri).unwrap());
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("b"));
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_reload_toggled() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        let reloader = |dispatcher: &mut Dispatcher| {
+            dispatcher
+                .use_backend(Echo("a"), 10)
+                .use_backend(Echo("b"), 0);
+            let config = Config::from_toml(r#"
+                [backends.b]
+                enabled = false
+            "#)?;
+            dispatcher.apply_config(&config)
+        };
+        reloader(&mut dispatcher).unwrap();
+        dispatcher.use_reloader(reloader);
+        assert!(dispatcher.disable_backend("a"));
+        assert!(dispatcher.enable_backend("b"));
+        assert!(dispatcher.reload(&peri).unwrap().is_empty());
+        assert_eq!(dispatcher.is_backend_enabled("a"), Some(false));
+        assert_eq!(dispatcher.is_backend_enabled("b"), Some(true));
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in()), reply("b"));
+    }
+    #[test]
+    fn test_reload_restart_needed() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.use_reloader(|dispatcher| {
+            dispatcher
+                .note_restart_needed("limits")
+                .use_backend(Echo("a"), 0);
+            Ok(())
+        });
+        assert_eq!(dispatcher.reload(&peri).unwrap(), vec!["limits"]);
+        assert_eq!(dispatcher.backends()[0].identity, "a");
+    }
+    #[test]
+    fn test_storage() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_backend(Counter("a"), 10)
+            .use_backend(Counter("b"), 0);
+        dispatcher.dispatch(&peri, &make_msg_in());
+        dispatcher.disable_backend("b");
+        dispatcher.dispatch(&peri, &make_msg_in());
+        let n = |owner| {
+            dispatcher.storage.get(owner, Scope::User(1), "n").unwrap()
+        };
+        assert_eq!(n("a"), Some("2".to_owned()));
+        assert_eq!(n("b"), Some("1".to_owned()));
+    }
+    #[test]
+    fn test_history() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_disabled_policy(DisabledPolicy::Queue(1))
+            .use_history(History::open_in_memory().unwrap())
+            .use_backend(Echo("a"), 0);
+        dispatcher.handle(&peri, &make_msg_in()).unwrap();
+        dispatcher.on_lifecycle(&peri, Lifecycle::Enable).unwrap();
+        let records = dispatcher.history().unwrap()
+            .last_in_private(1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.outgoing, record.content))
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![
+            (false, ::msg::text("hello")),
+            (true, ::msg::text("a")),
+        ]);
+    }
+}
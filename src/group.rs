@@ -0,0 +1,129 @@
+//! Group administration for backends, like muting, kicking and renaming
+//! members. The bot has to be an admin of the group, or the owner for some
+//! actions.
+//!
+//! ```ignore
+//! let grp = ctx.group(grp);
+//! grp.ban(qq, Duration::from_secs(600))?;
+//! grp.set_card(qq, "spammer")?;
+//! ```
+use std::time::Duration;
+use msg::Anonymous;
+use peripheral::{AdminError, Peripheral};
+
+/// Longest ban the platform allows, of 30 days.
+pub const MAX_BAN: u64 = 30 * 24 * 3600;
+
+/// Ban duration in seconds, checked against the range the platform allows.
+fn ban_secs(duration: Duration) -> Result<i64, AdminError> {
+    let secs = duration.as_secs();
+    if secs == 0 || secs > MAX_BAN {
+        let reason = format!("ban duration should be 1 to {} seconds",
+                             MAX_BAN);
+        return Err(AdminError::Invalid(reason))
+    }
+    Ok(secs as i64)
+}
+
+/// Actions on a group.
+#[derive(Clone, Copy)]
+pub struct GroupAdmin<'a> {
+    peripheral: &'a Peripheral,
+    grp: i64,
+}
+impl<'a> GroupAdmin<'a> {
+    pub fn new(peripheral: &'a Peripheral, grp: i64) -> GroupAdmin<'a> {
+        GroupAdmin {
+            peripheral: peripheral,
+            grp: grp,
+        }
+    }
+    pub fn grp(&self) -> i64 {
+        self.grp
+    }
+    /// Ban a member from speaking for `duration`, rounded down to seconds.
+    pub fn ban(&self, qq: i64, duration: Duration) -> Result<(), AdminError> {
+        self.peripheral.ban(self.grp, qq, ban_secs(duration)?)
+    }
+    pub fn unban(&self, qq: i64) -> Result<(), AdminError> {
+        self.peripheral.ban(self.grp, qq, 0)
+    }
+    /// Ban an anonymous member from speaking for `duration`. Anonymous bans
+    /// can't be lifted.
+    pub fn ban_anonymous(&self, anon: &Anonymous, duration: Duration)
+            -> Result<(), AdminError> {
+        self.peripheral.ban_anonymous(self.grp, anon, ban_secs(duration)?)
+    }
+    /// Kick a member. Their later requests to join are rejected at once if
+    /// `reject_rejoin` is true.
+    pub fn kick(&self, qq: i64, reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        self.peripheral.kick(self.grp, qq, reject_rejoin)
+    }
+    /// Set the group card of a member. An empty card clears it.
+    pub fn set_card(&self, qq: i64, card: &str) -> Result<(), AdminError> {
+        self.peripheral.set_card(self.grp, qq, card)
+    }
+    /// Make a member an admin, or no longer an admin. Only the owner can.
+    pub fn set_admin(&self, qq: i64, admin: bool) -> Result<(), AdminError> {
+        self.peripheral.set_admin(self.grp, qq, admin)
+    }
+    /// Ban all members but admins from speaking, or lift the ban.
+    pub fn set_whole_ban(&self, ban: bool) -> Result<(), AdminError> {
+        self.peripheral.set_whole_ban(self.grp, ban)
+    }
+    /// Give a member a special title, expiring after `duration` or never.
+    /// An empty title clears it. Only the owner can.
+    pub fn set_special_title(&self, qq: i64, title: &str,
+                             duration: Option<Duration>)
+            -> Result<(), AdminError> {
+        let secs = match duration {
+            Some(duration) if duration.as_secs() > 0 => {
+                duration.as_secs() as i64
+            },
+            Some(_) => {
+                let reason = "title should last at least a second".to_owned();
+                return Err(AdminError::Invalid(reason))
+            },
+            None => -1,
+        };
+        self.peripheral.set_special_title(self.grp, qq, title, secs)
+    }
+    /// Leave the group. If the bot is the owner, the group is disposed if
+    /// `dispose` is true, or nothing is done otherwise.
+    pub fn leave(&self, dispose: bool) -> Result<(), AdminError> {
+        self.peripheral.leave_group(self.grp, dispose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peripheral::memory::{Action, MemoryPeripheral};
+
+    #[test]
+    fn test_actions() {
+        let peri = MemoryPeripheral::new();
+        let grp = GroupAdmin::new(&peri, 1);
+        grp.ban(2, Duration::from_millis(60500)).unwrap();
+        grp.unban(2).unwrap();
+        grp.set_special_title(2, "penguin", None).unwrap();
+        assert_eq!(peri.take_actions(), vec![
+            Action::Ban { grp: 1, qq: 2, duration: 60 },
+            Action::Ban { grp: 1, qq: 2, duration: 0 },
+            Action::SetSpecialTitle {
+                grp: 1,
+                qq: 2,
+                title: "penguin".to_owned(),
+                duration: -1,
+            },
+        ]);
+        let too_long = Duration::from_secs(MAX_BAN + 1);
+        match grp.ban(2, too_long) {
+            Err(AdminError::Invalid(_)) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(grp.ban(2, Duration::from_millis(10)).is_err());
+        assert!(peri.take_actions().is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+//! Message history kept in a SQLite database. Every incoming message and
+//! every message sent by the bot is recorded, so that backends can look back
+//! at what has been said.
+use std::time::{SystemTime, UNIX_EPOCH};
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use failure::Error;
+use {Msg, MsgIn};
+use msg::Conversation;
+use schema::{self, history};
+
+#[derive(Queryable)]
+struct Row {
+    id: i64,
+    outgoing: bool,
+    qq: Option<i64>,
+    grp: Option<i64>,
+    alias: String,
+    timestamp: i64,
+    content: String,
+    discuss: Option<i64>,
+}
+
+#[derive(Insertable)]
+#[table_name="history"]
+struct NewRow<'a> {
+    outgoing: bool,
+    qq: Option<i64>,
+    grp: Option<i64>,
+    alias: &'a str,
+    timestamp: i64,
+    content: &'a str,
+    discuss: Option<i64>,
+}
+
+/// A recorded message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub id: i64,
+    /// Whether the message was sent by the bot.
+    pub outgoing: bool,
+    /// Sender of an incoming message, or receiver of an outgoing private
+    /// message.
+    pub qq: Option<i64>,
+    /// Group the message was sent in, if any.
+    pub grp: Option<i64>,
+    /// Discuss group the message was sent in, if any.
+    pub discuss: Option<i64>,
+    /// Alias of the sender. Empty for outgoing messages.
+    pub alias: String,
+    /// Seconds since UNIX epoch.
+    pub timestamp: i64,
+    pub content: Msg,
+}
+impl Record {
+    fn from_row(row: Row) -> Result<Record, Error> {
+        let rv = Record {
+            id: row.id,
+            outgoing: row.outgoing,
+            qq: row.qq,
+            grp: row.grp,
+            discuss: row.discuss,
+            alias: row.alias,
+            timestamp: row.timestamp,
+            content: ::msg::from_json(&row.content)?,
+        };
+        Ok(rv)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub struct History {
+    conn: SqliteConnection,
+}
+impl History {
+    /// Open the database at `path`, creating it if it doesn't exist. Pending
+    /// migrations are run.
+    pub fn open(path: &str) -> Result<History, Error> {
+        Ok(History { conn: schema::establish(path)? })
+    }
+    /// Open a database living only in memory, for testing.
+    pub fn open_in_memory() -> Result<History, Error> {
+        History::open(":memory:")
+    }
+
+    fn insert(&self, row: NewRow) -> Result<(), Error> {
+        ::diesel::insert_into(history::table)
+            .values(&row)
+            .execute(&self.conn)?;
+        Ok(())
+    }
+    /// Record a message received.
+    pub fn record_in(&self, msg_in: &MsgIn) -> Result<(), Error> {
+        let (grp, discuss) = match msg_in.conversation() {
+            Conversation::Private(_) => (None, None),
+            Conversation::Group(grp) => (Some(grp), None),
+            Conversation::Discuss(discuss) => (None, Some(discuss)),
+        };
+        let alias = match msg_in {
+            MsgIn::Private { ref alias, .. } => alias,
+            MsgIn::Group { ref alias, .. } => alias,
+            MsgIn::Discuss { ref alias, .. } => alias,
+        };
+        self.insert(NewRow {
+            outgoing: false,
+            qq: Some(msg_in.qq()),
+            grp: grp,
+            alias: alias,
+            timestamp: now(),
+            content: &::msg::to_json(msg_in.content())?,
+            discuss: discuss,
+        })
+    }
+    /// Record a message sent to a user in private chat.
+    pub fn record_priv_out(&self, qq: i64, msg: &Msg) -> Result<(), Error> {
+        self.insert(NewRow {
+            outgoing: true,
+            qq: Some(qq),
+            grp: None,
+            alias: "",
+            timestamp: now(),
+            content: &::msg::to_json(msg)?,
+            discuss: None,
+        })
+    }
+    /// Record a message sent to a group.
+    pub fn record_grp_out(&self, grp: i64, msg: &Msg) -> Result<(), Error> {
+        self.insert(NewRow {
+            outgoing: true,
+            qq: None,
+            grp: Some(grp),
+            alias: "",
+            timestamp: now(),
+            content: &::msg::to_json(msg)?,
+            discuss: None,
+        })
+    }
+    /// Record a message sent to a discuss group.
+    pub fn record_discuss_out(&self, discuss: i64, msg: &Msg)
+            -> Result<(), Error> {
+        self.insert(NewRow {
+            outgoing: true,
+            qq: None,
+            grp: None,
+            alias: "",
+            timestamp: now(),
+            content: &::msg::to_json(msg)?,
+            discuss: Some(discuss),
+        })
+    }
+
+    fn collect(rows: Vec<Row>) -> Result<Vec<Record>, Error> {
+        // Rows are queried latest first.
+        rows.into_iter()
+            .rev()
+            .map(Record::from_row)
+            .collect()
+    }
+    /// The last `n` messages in a group, including those sent by the bot, in
+    /// chronological order.
+    pub fn last_in_group(&self, grp: i64, n: usize)
+            -> Result<Vec<Record>, Error> {
+        let rows = history::table
+            .filter(history::grp.eq(grp))
+            .order(history::id.desc())
+            .limit(n as i64)
+            .load::<Row>(&self.conn)?;
+        History::collect(rows)
+    }
+    /// The last `n` messages in a discuss group, including those sent by the
+    /// bot, in chronological order.
+    pub fn last_in_discuss(&self, discuss: i64, n: usize)
+            -> Result<Vec<Record>, Error> {
+        let rows = history::table
+            .filter(history::discuss.eq(discuss))
+            .order(history::id.desc())
+            .limit(n as i64)
+            .load::<Row>(&self.conn)?;
+        History::collect(rows)
+    }
+    /// The last `n` messages in private chat with a user, including those
+    /// sent by the bot, in chronological order.
+    pub fn last_in_private(&self, qq: i64, n: usize)
+            -> Result<Vec<Record>, Error> {
+        let rows = history::table
+            .filter(history::grp.is_null())
+            .filter(history::discuss.is_null())
+            .filter(history::qq.eq(qq))
+            .order(history::id.desc())
+            .limit(n as i64)
+            .load::<Row>(&self.conn)?;
+        History::collect(rows)
+    }
+    /// The last `n` messages a user has sent, anywhere, in chronological
+    /// order.
+    pub fn last_from_user(&self, qq: i64, n: usize)
+            -> Result<Vec<Record>, Error> {
+        let rows = history::table
+            .filter(history::outgoing.eq(false))
+            .filter(history::qq.eq(qq))
+            .order(history::id.desc())
+            .limit(n as i64)
+            .load::<Row>(&self.conn)?;
+        History::collect(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::text;
+
+    fn grp_msg_in(grp: i64, qq: i64, content: &str) -> MsgIn {
+        MsgIn::Group {
+            grp: grp,
+            qq: qq,
+            alias: qq.to_string(),
+            grp_alias: String::new(),
+            content: text(content),
+            anon: None,
+        }
+    }
+    fn contents(records: Vec<Record>) -> Vec<Msg> {
+        records.into_iter()
+            .map(|record| record.content)
+            .collect()
+    }
+    #[test]
+    fn test_query() {
+        let history = History::open_in_memory().unwrap();
+        history.record_in(&grp_msg_in(1, 10, "a")).unwrap();
+        history.record_in(&grp_msg_in(2, 10, "b")).unwrap();
+        history.record_grp_out(1, &text("c")).unwrap();
+        history.record_in(&grp_msg_in(1, 11, "d")).unwrap();
+        history.record_in(&MsgIn::Private {
+            qq: 10,
+            alias: "10".to_owned(),
+            content: text("e"),
+        }).unwrap();
+        history.record_priv_out(10, &text("f")).unwrap();
+        history.record_in(&MsgIn::Discuss {
+            discuss: 1,
+            qq: 10,
+            alias: "10".to_owned(),
+            content: text("g"),
+        }).unwrap();
+        history.record_discuss_out(1, &text("h")).unwrap();
+
+        assert_eq!(contents(history.last_in_group(1, 2).unwrap()),
+                   vec![text("c"), text("d")]);
+        assert_eq!(contents(history.last_in_group(1, 10).unwrap()),
+                   vec![text("a"), text("c"), text("d")]);
+        assert_eq!(contents(history.last_from_user(10, 10).unwrap()),
+                   vec![text("a"), text("b"), text("e"), text("g")]);
+        assert_eq!(contents(history.last_in_private(10, 10).unwrap()),
+                   vec![text("e"), text("f")]);
+        assert_eq!(contents(history.last_in_discuss(1, 10).unwrap()),
+                   vec![text("g"), text("h")]);
+
+        let record = history.last_in_group(2, 1).unwrap().remove(0);
+        assert!(!record.outgoing);
+        assert_eq!((record.qq, record.grp), (Some(10), Some(2)));
+        assert_eq!(record.alias, "10");
+        assert!(record.timestamp > 0);
+    }
+}
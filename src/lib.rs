@@ -5,18 +5,41 @@ extern crate encoding_rs;
 extern crate serde;
 extern crate serde_json;
 #[macro_use]
-extern crate structopt;
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate dotenv;
 extern crate failure;
+#[macro_use]
+extern crate log;
+extern crate toml;
+extern crate tungstenite;
 
-mod backend;
-mod composer;
-mod dispatcher;
 #[macro_use]
-mod msg;
-mod peripheral;
+pub mod msg;
+pub mod admin;
+pub mod backend;
+pub mod command;
+pub mod composer;
+pub mod config;
+pub mod dispatcher;
+pub mod group;
+pub mod history;
+pub mod limit;
+pub mod logger;
+pub mod middleware;
+pub mod peripheral;
+pub mod request;
+pub mod role;
+mod schema;
+pub mod segment;
+pub mod storage;
+#[cfg(windows)]
 pub mod sys;
+pub mod unwind;
 
+use std::path::Path;
+use failure::Error;
 use backend::Backend;
 use composer::Composer;
 use dispatcher::Dispatcher;
@@ -27,10 +50,62 @@ pub fn on_launch() {
 pub fn on_shutdown() {
 
 }
-pub fn on_configure(dispatcher: &mut Dispatcher) {
-    use peripheral::coolq::CoolQComposer;
+/// Register backends and apply `config`. It's run again on a blank
+/// dispatcher on reload.
+fn configure_backends(dispatcher: &mut Dispatcher, config: &config::Config)
+        -> Result<(), Error> {
+    dispatcher
+        .use_backend(admin::admin_backend(), 1000)
+        .use_backend(request::RequestBackend::new(), 900);
+    dispatcher.apply_config(config)
+}
+/// Set up the dispatcher with config loaded from `app_dir`, speaking the
+/// message format of `composer`. The backends are reloaded when the config is
+/// changed.
+pub fn on_configure<C>(dispatcher: &mut Dispatcher, app_dir: &Path,
+                       composer: C) -> Result<(), Error>
+        where C: 'static + Composer {
+    use config::Config;
+    use history::History;
+    use role::Roles;
+    use storage::SqliteStorage;
 
-    let local = std::env::current_dir().unwrap();
+    let config = Config::load(app_dir)?;
+    dispatcher
+        .use_composer(composer);
+    // Keep states and history across restarts. Changing the database needs a
+    // restart.
+    if let Some(ref db) = config.database {
+        let db = db.to_string_lossy();
+        dispatcher
+            .use_storage(SqliteStorage::open(&db)?)
+            .use_history(History::open(&db)?);
+    }
+    let roles = config.owners.iter()
+        .fold(Roles::new(), |roles, &qq| roles.with_owner(qq));
+    let roles = config.admins.iter()
+        .fold(roles, |roles, &qq| roles.with_admin(qq));
     dispatcher
-        .use_composer(CoolQComposer::new(&local));
+        .use_roles(roles)
+        .use_limits(config.limits);
+    configure_backends(dispatcher, &config)?;
+    for path in Config::paths(app_dir) {
+        dispatcher.use_watched_file(path);
+    }
+    let app_dir = app_dir.to_owned();
+    dispatcher.use_reloader(move |dispatcher| {
+        let fresh = Config::load(&app_dir)?;
+        // Those are set up only once, in this function.
+        if fresh.database != config.database {
+            dispatcher.note_restart_needed("database");
+        }
+        if fresh.owners != config.owners || fresh.admins != config.admins {
+            dispatcher.note_restart_needed("owners and admins");
+        }
+        if fresh.limits != config.limits {
+            dispatcher.note_restart_needed("limits");
+        }
+        configure_backends(dispatcher, &fresh)
+    });
+    Ok(())
 }
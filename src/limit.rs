@@ -0,0 +1,290 @@
+//! Rate limiting with token buckets, so that spammers can't make the bot
+//! reply to every message they send, and the bot doesn't send so much as to
+//! get its account throttled.
+//!
+//! Each user, each group and the whole bot have a bucket of tokens refilled
+//! at a constant rate. An incoming message is let through only if the
+//! buckets of its sender and its group both have a token to take. Each
+//! message the bot sends takes a token from the bucket of the whole bot.
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Instant;
+use msg::{Conversation, Msg, MsgIn};
+use storage::Scope;
+
+/// Rate of a token bucket.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    /// Number of tokens a full bucket has.
+    pub burst: u32,
+    /// Number of tokens refilled per minute.
+    pub per_minute: u32,
+}
+
+/// Limits of each kind of bucket. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Limit of each sender.
+    pub user: Option<Rate>,
+    /// Limit of each group, and each discuss group.
+    pub group: Option<Rate>,
+    /// Limit of all messages sent by the bot. Only the shared limits have
+    /// it counted, not those of backends.
+    pub global: Option<Rate>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Whether the user has been noticed since the bucket ran out.
+    noticed: bool,
+}
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate.burst as f64,
+            last: now,
+            noticed: false,
+        }
+    }
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        if now > self.last {
+            let dur = now - self.last;
+            let secs = dur.as_secs() as f64 + dur.subsec_nanos() as f64 * 1e-9;
+            self.tokens += secs * rate.per_minute as f64 / 60.0;
+            self.tokens = self.tokens.min(rate.burst as f64);
+            self.last = now;
+        }
+    }
+    /// Whether the bucket is as good as a new one.
+    fn is_full(&self, rate: Rate) -> bool {
+        self.tokens >= rate.burst as f64
+    }
+}
+
+/// Number of buckets kept before full ones are dropped.
+const MIN_SWEEP: usize = 64;
+
+/// What to do with a message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Deny the message, and tell the user to slow down.
+    Notice,
+    /// Deny the message silently, the user has been told.
+    Deny,
+}
+
+/// Tell the user to slow down.
+pub fn notice() -> Msg {
+    ::msg::text("Slow down, please. Try again later.")
+}
+
+pub struct RateLimiter {
+    limits: Limits,
+    buckets: RefCell<HashMap<Scope, Bucket>>,
+    /// Number of buckets at which full ones are dropped next time.
+    sweep_at: Cell<usize>,
+}
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter {
+            limits: limits,
+            buckets: RefCell::new(HashMap::new()),
+            sweep_at: Cell::new(MIN_SWEEP),
+        }
+    }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    /// Check whether the incoming message is allowed at `now`, and take a
+    /// token from each bucket it falls into if so. Only one notice is given
+    /// until the buckets are refilled.
+    pub fn check(&self, msg_in: &MsgIn, now: Instant) -> Verdict {
+        let mut scopes = Vec::with_capacity(2);
+        if let Some(rate) = self.limits.user {
+            // Anonymous members all share a number, so they are told apart
+            // by anonymous ids.
+            let scope = match msg_in.anon() {
+                Some(anon) => Scope::Anonymous(anon.id),
+                None => Scope::User(msg_in.qq()),
+            };
+            scopes.push((scope, rate));
+        }
+        if let Some(rate) = self.limits.group {
+            match msg_in.conversation() {
+                Conversation::Group(grp) => {
+                    scopes.push((Scope::Group(grp), rate))
+                },
+                Conversation::Discuss(discuss) => {
+                    scopes.push((Scope::Discuss(discuss), rate))
+                },
+                Conversation::Private(_) => {},
+            }
+        }
+        self.take(&scopes, now)
+    }
+    /// Check whether the bot can send a message at `now`, and take a token
+    /// from the global bucket if so.
+    pub fn check_send(&self, now: Instant) -> bool {
+        match self.limits.global {
+            Some(rate) => {
+                self.take(&[(Scope::Global, rate)], now) == Verdict::Allow
+            },
+            None => true,
+        }
+    }
+    fn rate_of(&self, scope: Scope) -> Option<Rate> {
+        match scope {
+            Scope::User(_) | Scope::Anonymous(_) => self.limits.user,
+            Scope::Group(_) | Scope::Discuss(_) => self.limits.group,
+            Scope::Global => self.limits.global,
+        }
+    }
+    /// Drop buckets refilled to full, which would be created anew when
+    /// needed, so that senders seen only once don't take memory forever.
+    fn sweep(&self, now: Instant) {
+        let mut buckets = self.buckets.borrow_mut();
+        if buckets.len() < self.sweep_at.get() {
+            return
+        }
+        buckets.retain(|&scope, bucket| match self.rate_of(scope) {
+            Some(rate) => {
+                bucket.refill(rate, now);
+                !bucket.is_full(rate)
+            },
+            None => false,
+        });
+        // Sweep again only when the buckets have doubled, so that it costs
+        // little on average.
+        self.sweep_at.set((buckets.len() * 2).max(MIN_SWEEP));
+    }
+    fn take(&self, scopes: &[(Scope, Rate)], now: Instant) -> Verdict {
+        self.sweep(now);
+        let mut buckets = self.buckets.borrow_mut();
+        let mut allowed = true;
+        for &(scope, rate) in scopes.iter() {
+            let bucket = buckets.entry(scope)
+                .or_insert_with(|| Bucket::new(rate, now));
+            bucket.refill(rate, now);
+            allowed &= bucket.tokens >= 1.0;
+        }
+        let mut verdict = if allowed { Verdict::Allow } else { Verdict::Deny };
+        for &(scope, _) in scopes.iter() {
+            let bucket = buckets.get_mut(&scope).unwrap();
+            if allowed {
+                bucket.tokens -= 1.0;
+                bucket.noticed = false;
+            } else if bucket.tokens < 1.0 && !bucket.noticed {
+                bucket.noticed = true;
+                verdict = Verdict::Notice;
+            }
+        }
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use msg::Anonymous;
+
+    fn make_msg_in(grp: i64, qq: i64) -> MsgIn {
+        MsgIn::Group {
+            grp: grp,
+            qq: qq,
+            alias: String::new(),
+            grp_alias: String::new(),
+            content: ::msg::text(""),
+            anon: None,
+        }
+    }
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(Limits {
+            user: Some(Rate { burst: 2, per_minute: 6 }),
+            group: Some(Rate { burst: 3, per_minute: 60 }),
+            global: None,
+        });
+        let t0 = Instant::now();
+        let check = |grp, qq, secs| {
+            limiter.check(&make_msg_in(grp, qq), t0 + Duration::from_secs(secs))
+        };
+        assert_eq!(check(1, 1, 0), Verdict::Allow);
+        assert_eq!(check(1, 1, 0), Verdict::Allow);
+        assert_eq!(check(1, 1, 0), Verdict::Notice);
+        assert_eq!(check(1, 1, 1), Verdict::Deny);
+        // Others in the group are only limited by the group bucket.
+        assert_eq!(check(1, 2, 1), Verdict::Allow);
+        assert_eq!(check(1, 3, 1), Verdict::Allow);
+        assert_eq!(check(1, 4, 1), Verdict::Notice);
+        assert_eq!(check(2, 4, 1), Verdict::Allow);
+        // A token is refilled every 10 seconds for each user.
+        assert_eq!(check(1, 1, 10), Verdict::Allow);
+        assert_eq!(check(1, 1, 10), Verdict::Notice);
+    }
+    #[test]
+    fn test_anonymous() {
+        let limiter = RateLimiter::new(Limits {
+            user: Some(Rate { burst: 1, per_minute: 1 }),
+            ..Default::default()
+        });
+        let anon_msg_in = |id| {
+            let mut msg_in = make_msg_in(1, 80000000);
+            if let MsgIn::Group { ref mut anon, .. } = msg_in {
+                *anon = Some(Anonymous {
+                    id: id,
+                    name: String::new(),
+                    token: String::new(),
+                });
+            }
+            msg_in
+        };
+        let t0 = Instant::now();
+        assert_eq!(limiter.check(&anon_msg_in(5), t0), Verdict::Allow);
+        assert_eq!(limiter.check(&anon_msg_in(5), t0), Verdict::Notice);
+        assert_eq!(limiter.check(&anon_msg_in(6), t0), Verdict::Allow);
+        assert_eq!(limiter.check(&make_msg_in(1, 80000000), t0),
+                   Verdict::Allow);
+    }
+    #[test]
+    fn test_check_send() {
+        let limiter = RateLimiter::new(Limits {
+            global: Some(Rate { burst: 2, per_minute: 60 }),
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+        // Incoming messages don't count.
+        assert_eq!(limiter.check(&make_msg_in(1, 1), t0), Verdict::Allow);
+        assert!(limiter.check_send(t0));
+        assert!(limiter.check_send(t0));
+        assert!(!limiter.check_send(t0));
+        assert!(limiter.check_send(t0 + Duration::from_secs(1)));
+    }
+    #[test]
+    fn test_sweep() {
+        let limiter = RateLimiter::new(Limits {
+            user: Some(Rate { burst: 1, per_minute: 60 }),
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+        for qq in 0..MIN_SWEEP as i64 {
+            assert_eq!(limiter.check(&make_msg_in(1, qq), t0), Verdict::Allow);
+        }
+        // Nobody is refilled yet.
+        assert_eq!(limiter.check(&make_msg_in(1, 0), t0), Verdict::Notice);
+        assert_eq!(limiter.buckets.borrow().len(), MIN_SWEEP);
+        let t1 = t0 + Duration::from_secs(MIN_SWEEP as u64);
+        for qq in MIN_SWEEP as i64..MIN_SWEEP as i64 * 2 {
+            assert_eq!(limiter.check(&make_msg_in(1, qq), t1), Verdict::Allow);
+        }
+        assert_eq!(limiter.buckets.borrow().len(), MIN_SWEEP * 2);
+        // The first ones have been refilled, and are dropped on the next
+        // check.
+        assert_eq!(limiter.check(&make_msg_in(1, -1), t1), Verdict::Allow);
+        assert_eq!(limiter.buckets.borrow().len(), MIN_SWEEP + 1);
+    }
+}
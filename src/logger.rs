@@ -0,0 +1,84 @@
+//! Loggers behind the `log` facade. CoolQ logs through `CQ_addLog`, see
+//! `sys`, while the console and the OneBot server, and tests, have no such
+//! thing and log to stderr instead.
+use std::io::{self, Write};
+use failure::{err_msg, Error};
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+/// Log content of a record, without the level and the target, which are
+/// put in their own places by CoolQ.
+pub fn content(record: &Record) -> String {
+    match (record.file(), record.line()) {
+        (Some(file), Some(line)) if record.level() >= log::Level::Debug => {
+            format!("{} ({}:{})", record.args(), file, line)
+        },
+        _ => record.args().to_string(),
+    }
+}
+
+/// Logger writing to stderr.
+pub struct StderrLogger;
+impl StderrLogger {
+    fn format(record: &Record) -> String {
+        format!("[{}] {}: {}", record.level(), record.target(),
+                content(record))
+    }
+}
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let stderr = io::stderr();
+            let _ = writeln!(stderr.lock(), "{}", StderrLogger::format(record));
+        }
+    }
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+static STDERR_LOGGER: StderrLogger = StderrLogger;
+
+/// Log records at `level` or above to stderr. It fails if a logger has
+/// already been installed.
+pub fn init_stderr(level: LevelFilter) -> Result<(), Error> {
+    log::set_logger(&STDERR_LOGGER)
+        .map_err(|err| err_msg(format!("unable to set logger: {}", err)))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Log warnings and errors in tests to stderr. It can be called any number
+/// of times.
+#[cfg(test)]
+pub fn init_test() {
+    let _ = init_stderr(LevelFilter::Warn);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(StderrLogger::format(&Record::builder()
+                       .args(format_args!("oops {}", 1))
+                       .level(Level::Warn)
+                       .target("liongbot::dispatcher")
+                       .file(Some("src/dispatcher.rs"))
+                       .line(Some(42))
+                       .build()),
+                   "[WARN] liongbot::dispatcher: oops 1");
+        assert_eq!(content(&Record::builder()
+                       .args(format_args!("details"))
+                       .level(Level::Debug)
+                       .target("liongbot::sys")
+                       .file(Some("src/sys.rs"))
+                       .line(Some(7))
+                       .build()),
+                   "details (src/sys.rs:7)");
+    }
+}
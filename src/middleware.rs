@@ -0,0 +1,136 @@
+//! Middlewares wrap around backends, for concerns shared by all of them.
+//!
+//! Incoming messages pass through middlewares in registration order before
+//! reaching backends, and outgoing messages pass through them in reverse
+//! order before being composed.
+use failure::Error;
+use backend::{Context, Outcome};
+use msg::{Msg, MsgIn};
+
+pub trait Middleware {
+    /// Name of the middleware, also used as its storage owner.
+    fn name(&self) -> &'static str;
+    /// Inspect or rewrite an incoming message before backends see it.
+    /// Returning an outcome stops the message from reaching later
+    /// middlewares and backends, as if a backend has given the outcome.
+    fn before(&self, _ctx: &Context, _msg_in: &mut MsgIn)
+            -> Result<Option<Outcome>, Error> {
+        Ok(None)
+    }
+    /// Transform an outgoing message before it's composed. This applies to
+    /// all messages sent through `Context`, not only replies.
+    fn after(&self, _ctx: &Context, msg: Msg) -> Result<Msg, Error> {
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{Backend, BackendMetadata};
+    use dispatcher::Dispatcher;
+    use msg::MsgBuilder;
+    use peripheral::memory::{MemoryPeripheral, Sent};
+
+    /// Trim the content, and answer pings on its own.
+    struct Trim;
+    impl Middleware for Trim {
+        fn name(&self) -> &'static str {
+            "test.trim"
+        }
+        fn before(&self, _: &Context, msg_in: &mut MsgIn)
+                -> Result<Option<Outcome>, Error> {
+            let trimmed = match msg_in.content() {
+                Msg::Text(ref x) => x.trim().to_owned(),
+                _ => return Ok(None),
+            };
+            if trimmed == "ping" {
+                return Ok(Some(Outcome::Reply(::msg::text("pong"))))
+            }
+            *msg_in.content_mut() = ::msg::text(&trimmed);
+            Ok(None)
+        }
+    }
+    /// Sign all outgoing messages.
+    struct Sign(&'static str);
+    impl Middleware for Sign {
+        fn name(&self) -> &'static str {
+            "test.sign"
+        }
+        fn after(&self, _: &Context, msg: Msg) -> Result<Msg, Error> {
+            Ok(msg![msg, ::msg::text(self.0)])
+        }
+    }
+    /// Panic on everything.
+    struct Panic;
+    impl Middleware for Panic {
+        fn name(&self) -> &'static str {
+            "test.panic"
+        }
+        fn before(&self, _: &Context, msg_in: &mut MsgIn)
+                -> Result<Option<Outcome>, Error> {
+            if *msg_in.content() == ::msg::text("boom") {
+                panic!("boom");
+            }
+            Ok(None)
+        }
+        fn after(&self, _: &Context, _: Msg) -> Result<Msg, Error> {
+            panic!("boom");
+        }
+    }
+    struct Echo;
+    impl Backend for Echo {
+        fn metadata(&self) -> BackendMetadata {
+            Default::default()
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, _: &Context, msg_in: &MsgIn)
+                -> Result<Outcome, Error> {
+            Ok(Outcome::Reply(msg_in.content().clone()))
+        }
+    }
+    #[test]
+    fn test_pipeline() {
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_middleware(Trim)
+            .use_middleware(Sign(" -a"))
+            .use_middleware(Sign(" -b"))
+            .use_backend(Echo, 0);
+        let make_msg_in = |content| MsgIn::Private {
+            qq: 1,
+            alias: "1".to_owned(),
+            content: ::msg::text(content),
+        };
+        dispatcher.handle(&peri, &make_msg_in("  hi  ")).unwrap();
+        dispatcher.handle(&peri, &make_msg_in(" ping")).unwrap();
+        assert_eq!(peri.take_sent(), vec![
+            Sent::Private(1, "hi -b -a".to_owned()),
+            Sent::Private(1, "pong -b -a".to_owned()),
+        ]);
+    }
+    #[test]
+    fn test_panic() {
+        ::logger::init_test();
+        let peri = MemoryPeripheral::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_middleware(Panic)
+            .use_backend(Echo, 0);
+        let make_msg_in = |content| MsgIn::Private {
+            qq: 1,
+            alias: "1".to_owned(),
+            content: ::msg::text(content),
+        };
+        assert_eq!(dispatcher.dispatch(&peri, &make_msg_in("boom")),
+                   Outcome::Fail(::unwind::apology()));
+        let err = dispatcher.handle(&peri, &make_msg_in("hi")).unwrap_err();
+        assert_eq!(err.to_string(), "middleware `test.panic` panicked");
+        assert!(peri.take_sent().is_empty());
+    }
+}
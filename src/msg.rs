@@ -1,8 +1,16 @@
 use std::path::{Path, PathBuf};
 use std::collections::BTreeMap;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
 use failure::{err_msg, Error};
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// Version of the serialized format of `Msg` and `MsgIn`. Bump it whenever
+/// the representation changes incompatibly.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum Msg {
     Text(String),
     Compound(Vec<Msg>),
@@ -241,6 +249,11 @@ pub fn record<P: ?Sized + AsRef<Path>>(path: &P) -> Msg {
 }
 
 pub struct MsgBuilder(Vec<Msg>);
+impl Default for MsgBuilder {
+    fn default() -> MsgBuilder {
+        MsgBuilder::new()
+    }
+}
 impl MsgBuilder {
     pub fn new() -> MsgBuilder {
         MsgBuilder(Vec::new())
@@ -280,6 +293,19 @@ macro_rules! msg {
     }}
 }
 
+/// Identity of an anonymous group member.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Anonymous {
+    pub id: i64,
+    /// Name randomly given by the platform, like `Anonymous Penguin`.
+    pub name: String,
+    /// Opaque token given by the peripheral, identifying the sender to
+    /// moderation actions.
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum MsgIn {
     Private {
         qq: i64,
@@ -292,23 +318,105 @@ pub enum MsgIn {
         alias: String,
         grp_alias: String,
         content: Msg,
-    }
+        /// Identity of the sender if it's anonymous. `qq` is then a number
+        /// shared by all anonymous senders.
+        #[serde(default)]
+        anon: Option<Anonymous>,
+    },
+    /// A message in a discuss group, a lightweight group without admins.
+    Discuss {
+        discuss: i64,
+        qq: i64,
+        alias: String,
+        content: Msg,
+    },
+}
+/// Where a message is sent.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Conversation {
+    /// Private chat with a user.
+    Private(i64),
+    Group(i64),
+    Discuss(i64),
 }
 impl MsgIn {
-    pub fn is_priv(&self) -> bool {
-        if let MsgIn::Private { .. } = self {
-            true
-        } else {
-            false
+    /// The user who sent the message.
+    pub fn qq(&self) -> i64 {
+        match self {
+            MsgIn::Private { qq, .. } => *qq,
+            MsgIn::Group { qq, .. } => *qq,
+            MsgIn::Discuss { qq, .. } => *qq,
         }
     }
-    pub fn is_grp(&self) -> bool {
-        if let MsgIn::Group { .. } = self {
-            true
-        } else {
-            false
+    pub fn content(&self) -> &Msg {
+        match self {
+            MsgIn::Private { ref content, .. } => content,
+            MsgIn::Group { ref content, .. } => content,
+            MsgIn::Discuss { ref content, .. } => content,
+        }
+    }
+    pub fn content_mut(&mut self) -> &mut Msg {
+        match self {
+            MsgIn::Private { ref mut content, .. } => content,
+            MsgIn::Group { ref mut content, .. } => content,
+            MsgIn::Discuss { ref mut content, .. } => content,
         }
     }
+    /// Identity of the sender if it's an anonymous group member.
+    pub fn anon(&self) -> Option<&Anonymous> {
+        match self {
+            MsgIn::Group { ref anon, .. } => anon.as_ref(),
+            _ => None,
+        }
+    }
+    /// The conversation the message is sent in, where replies should go.
+    pub fn conversation(&self) -> Conversation {
+        match self {
+            MsgIn::Private { qq, .. } => Conversation::Private(*qq),
+            MsgIn::Group { grp, .. } => Conversation::Group(*grp),
+            MsgIn::Discuss { discuss, .. } => Conversation::Discuss(*discuss),
+        }
+    }
+    pub fn is_priv(&self) -> bool {
+        matches!(self, MsgIn::Private { .. })
+    }
+    pub fn is_grp(&self) -> bool {
+        matches!(self, MsgIn::Group { .. })
+    }
+    pub fn is_discuss(&self) -> bool {
+        matches!(self, MsgIn::Discuss { .. })
+    }
+}
+
+#[derive(Serialize)]
+struct VersionedRef<'a, T: 'a> {
+    version: u32,
+    data: &'a T,
+}
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+    /// Only deserialized once the version is known to be supported.
+    data: serde_json::Value,
+}
+/// Serialize `Msg`, `MsgIn` or anything containing them into JSON tagged
+/// with `FORMAT_VERSION`.
+pub fn to_json<T: Serialize>(data: &T) -> Result<String, Error> {
+    let versioned = VersionedRef {
+        version: FORMAT_VERSION,
+        data: data,
+    };
+    Ok(serde_json::to_string(&versioned)?)
+}
+/// Deserialize JSON made by `to_json`. Data of other format versions are
+/// rejected.
+pub fn from_json<T: DeserializeOwned>(raw: &str) -> Result<T, Error> {
+    let versioned = serde_json::from_str::<Versioned>(raw)?;
+    if versioned.version != FORMAT_VERSION {
+        return Err(err_msg(format!("unsupported format version {}",
+                                   versioned.version)))
+    }
+    Ok(serde_json::from_value(versioned.data)?)
 }
 
 #[cfg(test)]
@@ -382,4 +490,33 @@ mod tests {
         assert_eq!(msg!["123", at(1)].starts_with("123"), true);
         assert_eq!(msg!["123", at(1)].starts_with("122"), false);
     }
+    #[test]
+    fn test_serde() {
+        let msg = msg!["123", at(123)];
+        let raw = to_json(&msg).unwrap();
+        assert_eq!(raw, concat!(r#"{"version":1,"data":{"type":"compound","#,
+                                r#""data":[{"type":"text","data":"123"},"#,
+                                r#"{"type":"ext","data":{"name":"at","#,
+                                r#""params":{"qq":"123"}}}]}}"#));
+        assert_eq!(from_json::<Msg>(&raw).unwrap(), msg);
+
+        let msg_in = MsgIn::Group {
+            grp: 1,
+            qq: 2,
+            alias: "a".to_owned(),
+            grp_alias: "b".to_owned(),
+            content: msg,
+            anon: None,
+        };
+        let raw = to_json(&msg_in).unwrap();
+        assert!(raw.starts_with(r#"{"version":1,"data":{"type":"group","#));
+        assert_eq!(from_json::<MsgIn>(&raw).unwrap(), msg_in);
+
+        let raw = r#"{"version":0,"data":{"type":"text","data":""}}"#;
+        assert!(from_json::<Msg>(raw).is_err());
+        // Whatever future formats look like.
+        let raw = r#"{"version":2,"data":{"type":"sticker","id":1}}"#;
+        assert_eq!(from_json::<Msg>(raw).unwrap_err().to_string(),
+                   "unsupported format version 2");
+    }
 }
@@ -0,0 +1,361 @@
+//! Local console peripheral, reading messages from a line-based input and
+//! writing the replies to an output. Lines starting with `/` are console
+//! commands switching the fake identity messages are sent as:
+//!
+//! * `/as <qq> [alias]` - Send as another user.
+//! * `/grp <grp> [alias]` - Send to a group.
+//! * `/discuss <discuss>` - Send to a discuss group.
+//! * `/priv` - Send in private chat.
+//! * `/whoami` - Show the current identity.
+//! * `/quit` - Stop reading input.
+//!
+//! A leading `//` is escaped as a single `/` in message text.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use failure::{err_msg, Error};
+use dispatcher::Dispatcher;
+use msg::{Anonymous, Conversation, MsgIn};
+use peripheral::{AdminError, Lifecycle, MemberInfo, Peripheral, UserInfo};
+
+/// Where messages are sent, with the alias in the group.
+enum Place {
+    Private,
+    Group(i64, String),
+    Discuss(i64),
+}
+
+struct Identity {
+    qq: i64,
+    alias: String,
+    place: Place,
+}
+
+pub struct Console<W: Write> {
+    identity: RefCell<Identity>,
+    /// Aliases of all the users ever impersonated.
+    aliases: RefCell<BTreeMap<i64, String>>,
+    output: RefCell<W>,
+}
+impl<W: Write> Console<W> {
+    pub fn new(output: W) -> Console<W> {
+        let identity = Identity {
+            qq: 10000,
+            alias: "console".to_owned(),
+            place: Place::Private,
+        };
+        let mut aliases = BTreeMap::new();
+        aliases.insert(identity.qq, identity.alias.clone());
+        Console {
+            identity: RefCell::new(identity),
+            aliases: RefCell::new(aliases),
+            output: RefCell::new(output),
+        }
+    }
+
+    fn whoami(&self) -> String {
+        let identity = self.identity.borrow();
+        match identity.place {
+            Place::Private => {
+                format!("{}({}) in private chat", identity.alias, identity.qq)
+            },
+            Place::Group(grp, ref grp_alias) => {
+                format!("{}({}) in group {}({})",
+                        identity.alias, identity.qq, grp_alias, grp)
+            },
+            Place::Discuss(discuss) => {
+                format!("{}({}) in discuss {}",
+                        identity.alias, identity.qq, discuss)
+            },
+        }
+    }
+    /// Whether messages are being sent to `conv`.
+    fn is_current(&self, conv: Conversation) -> bool {
+        let identity = self.identity.borrow();
+        let current = match identity.place {
+            Place::Private => Conversation::Private(identity.qq),
+            Place::Group(grp, _) => Conversation::Group(grp),
+            Place::Discuss(discuss) => Conversation::Discuss(discuss),
+        };
+        current == conv
+    }
+    fn alias_of(&self, qq: i64) -> String {
+        self.aliases.borrow().get(&qq)
+            .cloned()
+            .unwrap_or_else(|| qq.to_string())
+    }
+    fn make_msg_in(&self, dispatcher: &Dispatcher, line: &str)
+            -> Result<MsgIn, Error> {
+        let content = dispatcher.composer().decompose(line)?;
+        let identity = self.identity.borrow();
+        let rv = match identity.place {
+            Place::Private => MsgIn::Private {
+                qq: identity.qq,
+                alias: identity.alias.clone(),
+                content: content,
+            },
+            Place::Group(grp, ref grp_alias) => MsgIn::Group {
+                grp: grp,
+                qq: identity.qq,
+                alias: identity.alias.clone(),
+                grp_alias: grp_alias.clone(),
+                content: content,
+                anon: None,
+            },
+            Place::Discuss(discuss) => MsgIn::Discuss {
+                discuss: discuss,
+                qq: identity.qq,
+                alias: identity.alias.clone(),
+                content: content,
+            },
+        };
+        Ok(rv)
+    }
+    /// Execute a console command. `None` is returned when the console should
+    /// quit.
+    fn exec(&self, cmd: &str) -> Result<Option<String>, Error> {
+        let mut args = cmd.split_whitespace();
+        let name = args.next().unwrap_or("");
+        let id = args.next()
+            .map(|x| x.parse::<i64>()
+                .map_err(|_| err_msg(format!("invalid id `{}`", x))));
+        let alias = args.collect::<Vec<_>>().join(" ");
+        match (name, id) {
+            ("as", Some(qq)) => {
+                let qq = qq?;
+                let alias = if alias.is_empty() {
+                    self.alias_of(qq)
+                } else {
+                    alias
+                };
+                self.aliases.borrow_mut().insert(qq, alias.clone());
+                let mut identity = self.identity.borrow_mut();
+                identity.qq = qq;
+                identity.alias = alias;
+            },
+            ("grp", Some(grp)) => {
+                let grp = grp?;
+                let grp_alias = if alias.is_empty() {
+                    grp.to_string()
+                } else {
+                    alias
+                };
+                self.identity.borrow_mut().place = Place::Group(grp, grp_alias);
+            },
+            ("discuss", Some(discuss)) => {
+                self.identity.borrow_mut().place = Place::Discuss(discuss?);
+            },
+            ("priv", None) => self.identity.borrow_mut().place = Place::Private,
+            ("whoami", None) => {},
+            ("quit", None) => return Ok(None),
+            _ => return Err(err_msg(format!("unknown command `/{}`", cmd))),
+        }
+        Ok(Some(self.whoami()))
+    }
+    fn print(&self, line: &str) -> Result<(), Error> {
+        writeln!(self.output.borrow_mut(), "{}", line)?;
+        Ok(())
+    }
+    /// Print an action taken in place of the platform.
+    fn notice(&self, line: &str) -> Result<(), AdminError> {
+        Ok(self.print(line)?)
+    }
+    /// Read input line by line until the input is exhausted or `/quit` is
+    /// met. The dispatcher is launched and enabled before reading, and is
+    /// shut down afterwards.
+    pub fn run<R>(&self, dispatcher: &Dispatcher, input: R)
+            -> Result<(), Error> where R: BufRead {
+        dispatcher.on_lifecycle(self, Lifecycle::Launch)?;
+        dispatcher.on_lifecycle(self, Lifecycle::Enable)?;
+        for line in input.lines() {
+            let line = line?;
+            match dispatcher.poll_reload(self) {
+                Ok(true) => self.print("* config reloaded")?,
+                Ok(false) => {},
+                Err(err) => self.print(&format!("! reload failed: {}", err))?,
+            }
+            if line.starts_with('/') && !line.starts_with("//") {
+                match self.exec(&line[1..]) {
+                    Ok(Some(info)) => self.print(&format!("* {}", info))?,
+                    Ok(None) => break,
+                    Err(err) => self.print(&format!("! {}", err))?,
+                }
+                continue
+            }
+            let line = if line.starts_with("//") { &line[1..] } else { &line };
+            let rv = self.make_msg_in(dispatcher, line)
+                .and_then(|msg_in| dispatcher.handle(self, &msg_in));
+            if let Err(err) = rv {
+                self.print(&format!("! {}", err))?;
+            }
+        }
+        dispatcher.on_lifecycle(self, Lifecycle::Disable)?;
+        dispatcher.on_lifecycle(self, Lifecycle::Shutdown)?;
+        self.output.borrow_mut().flush()?;
+        Ok(())
+    }
+}
+impl<W: Write> Peripheral for Console<W> {
+    fn name(&self) -> &'static str {
+        "peripheral.console"
+    }
+    /// Messages to the current conversation are printed after `> `, others
+    /// are printed with their destination.
+    fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error> {
+        if self.is_current(Conversation::Private(qq)) {
+            self.print(&format!("> {}", raw))
+        } else {
+            self.print(&format!("> [to {}] {}", self.alias_of(qq), raw))
+        }
+    }
+    fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error> {
+        if self.is_current(Conversation::Group(grp)) {
+            self.print(&format!("> {}", raw))
+        } else {
+            self.print(&format!("> [to group {}] {}", grp, raw))
+        }
+    }
+    fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error> {
+        if self.is_current(Conversation::Discuss(discuss)) {
+            self.print(&format!("> {}", raw))
+        } else {
+            self.print(&format!("> [to discuss {}] {}", discuss, raw))
+        }
+    }
+    fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+        let rv = UserInfo {
+            qq: qq,
+            nickname: self.alias_of(qq),
+        };
+        Ok(rv)
+    }
+    fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+        let rv = MemberInfo {
+            grp: grp,
+            qq: qq,
+            nickname: self.alias_of(qq),
+            ..Default::default()
+        };
+        Ok(rv)
+    }
+    fn ban(&self, grp: i64, qq: i64, duration: i64)
+            -> Result<(), AdminError> {
+        let line = if duration == 0 {
+            format!("* {} is unbanned in group {}", self.alias_of(qq), grp)
+        } else {
+            format!("* {} is banned in group {} for {}s",
+                    self.alias_of(qq), grp, duration)
+        };
+        self.notice(&line)
+    }
+    fn ban_anonymous(&self, grp: i64, anon: &Anonymous, duration: i64)
+            -> Result<(), AdminError> {
+        self.notice(&format!("* {} is banned in group {} for {}s",
+                             anon.name, grp, duration))
+    }
+    fn kick(&self, grp: i64, qq: i64, _reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        self.notice(&format!("* {} is kicked from group {}",
+                             self.alias_of(qq), grp))
+    }
+    fn set_card(&self, grp: i64, qq: i64, card: &str)
+            -> Result<(), AdminError> {
+        self.notice(&format!("* {} is now known as {} in group {}",
+                             self.alias_of(qq), card, grp))
+    }
+    fn set_admin(&self, grp: i64, qq: i64, admin: bool)
+            -> Result<(), AdminError> {
+        let what = if admin { "now" } else { "no longer" };
+        self.notice(&format!("* {} is {} an admin of group {}",
+                             self.alias_of(qq), what, grp))
+    }
+    fn set_whole_ban(&self, grp: i64, ban: bool) -> Result<(), AdminError> {
+        let what = if ban { "banned" } else { "unbanned" };
+        self.notice(&format!("* Group {} is {} as a whole", grp, what))
+    }
+    fn set_special_title(&self, grp: i64, qq: i64, title: &str,
+                         _duration: i64) -> Result<(), AdminError> {
+        self.notice(&format!("* {} is titled {} in group {}",
+                             self.alias_of(qq), title, grp))
+    }
+    fn leave_group(&self, grp: i64, _dispose: bool)
+            -> Result<(), AdminError> {
+        self.notice(&format!("* Left group {}", grp))
+    }
+    fn leave_discuss(&self, discuss: i64) -> Result<(), AdminError> {
+        self.notice(&format!("* Left discuss {}", discuss))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{Backend, BackendMetadata, Context, Outcome};
+
+    /// Tell who sent the message and where.
+    struct Whoami;
+    impl Backend for Whoami {
+        fn metadata(&self) -> BackendMetadata {
+            BackendMetadata {
+                identity: "test.whoami",
+                ..Default::default()
+            }
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, ctx: &Context, msg_in: &MsgIn)
+                -> Result<Outcome, Error> {
+            if msg_in.content() == &::msg::text("dm") {
+                ctx.send_priv(1, &::msg::text("psst"))?;
+                return Ok(Outcome::Handled)
+            }
+            let reply = match msg_in {
+                MsgIn::Private { qq, ref content, .. } => {
+                    format!("{} {:?}", qq, content)
+                },
+                MsgIn::Group { grp, qq, ref content, .. } => {
+                    format!("{}@{} {:?}", qq, grp, content)
+                },
+                MsgIn::Discuss { discuss, qq, ref content, .. } => {
+                    format!("{}@discuss {} {:?}", qq, discuss, content)
+                },
+            };
+            Ok(Outcome::Reply(::msg::text(&reply)))
+        }
+    }
+    fn run(input: &str) -> String {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.use_backend(Whoami, 0);
+        let mut output = Vec::new();
+        Console::new(&mut output).run(&dispatcher, input.as_bytes())
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+    #[test]
+    fn test_identity() {
+        let output = run("hi\n\
+                          /as 123 Alice\n\
+                          /grp 456\n\
+                          //hey\n\
+                          dm\n\
+                          /discuss 789\n\
+                          yo\n\
+                          /priv\n\
+                          /quit\n\
+                          ignored\n");
+        assert_eq!(output, "> 10000 Text(\"hi\")\n\
+                            * Alice(123) in private chat\n\
+                            * Alice(123) in group 456(456)\n\
+                            > 123@456 Text(\"/hey\")\n\
+                            > [to 1] psst\n\
+                            * Alice(123) in discuss 789\n\
+                            > 123@discuss 789 Text(\"yo\")\n\
+                            * Alice(123) in private chat\n");
+    }
+    #[test]
+    fn test_bad_command() {
+        assert_eq!(run("/as abc\n/dance\n"),
+                   "! invalid id `abc`\n! unknown command `/dance`\n");
+    }
+}
@@ -1,7 +1,10 @@
 use std::path::{Path, PathBuf};
+use base64;
+use encoding_rs::GB18030;
 use failure::{err_msg, Error};
 use composer::Composer;
-use msg::{Msg, ExtBuilder, MsgBuilder};
+use msg::{Anonymous, Msg, ExtBuilder, MsgBuilder};
+use peripheral::{FileInfo, MemberInfo, MemberRole, UserInfo};
 
 fn extend_esc(string: &str, out: &mut String) {
     for c in string.chars() {
@@ -84,28 +87,6 @@ impl CoolQComposer {
         }
         Ok(())
     }
-    fn parse_cq(&self, string: &str) -> Result<Msg, Error> {
-        let mut iter = string.split(',');
-        let name = inverse_cq(iter.next()
-            .ok_or_else(|| err_msg("unable to parse cq code"))?);
-        let mut out_params = ExtBuilder::new(&name);
-        for param in iter {
-            let mut param = param.splitn(2, '=');
-            let key = inverse_cq(param.next().unwrap().trim());
-            let mut value = inverse_cq(param.next()
-                .ok_or_else(|| err_msg("missing parameter value"))?.trim());
-            // Translate path.
-            if key == "file" {
-                let mut path = self.data_dir.clone();
-                path.push(&name);
-                path.push(value);
-                out_params.add_param(&key, &path.to_string_lossy());
-            } else {
-                out_params.add_param(&key, &value);
-            }
-        }
-        Ok(out_params.build())
-    }
 }
 impl Composer for CoolQComposer {
     fn name(&self) -> &'static str {
@@ -117,31 +98,174 @@ impl Composer for CoolQComposer {
         Ok(out)
     }
     fn decompose(&self, raw: &str) -> Result<Msg, Error> {
-        use msg::text;
-        let mut beg = 0;
-        let mut rv = MsgBuilder::new();
-        while (beg < raw.len()) {
-            if let Some(from) = raw[beg..].find("[CQ:") {
-                if (from > 0) {
-                    rv.add_msg(Msg::Text(inverse(&raw[beg..(beg + from)])));
-                }
-                beg += from + 4; // Skip `[CQ:`.
-                let to = raw[beg..].find(']')
-                    .ok_or_else(|| err_msg("unclosed cq code"))?;
-                let cq = self.parse_cq(&raw[beg..(beg + to)])?;
-                rv.add_msg(cq);
-                beg += to + 1; // Skip `]`.
-            } else {
-                // Couldn't find a next CQ code.
-                break;
+        decompose_cq(raw, Some(&self.data_dir))
+    }
+}
+
+/// Parse a message in CQ codes. File params are translated into paths in
+/// `data_dir` if it's given.
+pub fn decompose_cq(raw: &str, data_dir: Option<&Path>)
+        -> Result<Msg, Error> {
+    let mut beg = 0;
+    let mut rv = MsgBuilder::new();
+    while beg < raw.len() {
+        if let Some(from) = raw[beg..].find("[CQ:") {
+            if from > 0 {
+                rv.add_msg(Msg::Text(inverse(&raw[beg..(beg + from)])));
             }
+            beg += from + 4; // Skip `[CQ:`.
+            let to = raw[beg..].find(']')
+                .ok_or_else(|| err_msg("unclosed cq code"))?;
+            let cq = parse_cq(&raw[beg..(beg + to)], data_dir)?;
+            rv.add_msg(cq);
+            beg += to + 1; // Skip `]`.
+        } else {
+            // Couldn't find a next CQ code.
+            break;
+        }
+    }
+    // Add the remaining segment.
+    if beg < raw.len() {
+        rv.add_msg(Msg::Text(inverse(&raw[beg..])));
+    }
+    Ok(rv.build())
+}
+fn parse_cq(string: &str, data_dir: Option<&Path>) -> Result<Msg, Error> {
+    let mut iter = string.split(',');
+    let name = inverse_cq(iter.next()
+        .ok_or_else(|| err_msg("unable to parse cq code"))?);
+    let mut out_params = ExtBuilder::new(&name);
+    for param in iter {
+        let mut param = param.splitn(2, '=');
+        let key = inverse_cq(param.next().unwrap().trim());
+        let value = inverse_cq(param.next()
+            .ok_or_else(|| err_msg("missing parameter value"))?.trim());
+        match data_dir {
+            // Translate path.
+            Some(data_dir) if key == "file" => {
+                let mut path = data_dir.to_owned();
+                path.push(&name);
+                path.push(value);
+                out_params.add_param(&key, &path.to_string_lossy());
+            },
+            _ => out_params.add_param(&key, &value),
+        }
+    }
+    Ok(out_params.build())
+}
+
+/// Reader of the binary structures CoolQ gives in base64. Integers are
+/// big-endian, and strings are GB18030 prefixed with a 16-bit length.
+pub struct Unpacker {
+    buf: Vec<u8>,
+    pos: usize,
+}
+impl Unpacker {
+    pub fn new(buf: Vec<u8>) -> Unpacker {
+        Unpacker {
+            buf: buf,
+            pos: 0,
+        }
+    }
+    pub fn from_base64<T>(b64: &T) -> Result<Unpacker, Error>
+            where T: ?Sized + AsRef<[u8]> {
+        Ok(Unpacker::new(base64::decode(b64)?))
+    }
+    pub fn bytes(&mut self, len: usize) -> Result<&[u8], Error> {
+        if self.buf.len() - self.pos < len {
+            return Err(err_msg("unexpected end of data"))
         }
-        // Add the remaining segment.
-        if (beg < raw.len()) {
-            rv.add_msg(Msg::Text(inverse(&raw[beg..])));
+        let rv = &self.buf[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(rv)
+    }
+    fn int(&mut self, len: usize) -> Result<i64, Error> {
+        let rv = self.bytes(len)?.iter()
+            .fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        // Sign-extend to 64 bits.
+        let shift = 64 - len * 8;
+        Ok(((rv << shift) as i64) >> shift)
+    }
+    pub fn i16(&mut self) -> Result<i16, Error> {
+        self.int(2).map(|x| x as i16)
+    }
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        self.int(4).map(|x| x as i32)
+    }
+    pub fn i64(&mut self) -> Result<i64, Error> {
+        self.int(8)
+    }
+    pub fn len_bytes(&mut self) -> Result<&[u8], Error> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Err(err_msg("negative length"))
         }
-        Ok(rv.build())
+        self.bytes(len as usize)
     }
+    pub fn len_str(&mut self) -> Result<String, Error> {
+        let (rv, _) = GB18030.decode_without_bom_handling(self.len_bytes()?);
+        Ok(rv.into_owned())
+    }
+}
+
+/// Parse the result of `CQ_getStrangerInfo`.
+pub fn parse_user_info(b64: &[u8]) -> Result<UserInfo, Error> {
+    let mut b = Unpacker::from_base64(b64)?;
+    let rv = UserInfo {
+        qq: b.i64()?,
+        nickname: b.len_str()?,
+    };
+    Ok(rv)
+}
+/// Parse the result of `CQ_getGroupMemberInfoV2`.
+pub fn parse_member_info(b64: &[u8]) -> Result<MemberInfo, Error> {
+    let mut b = Unpacker::from_base64(b64)?;
+    let grp = b.i64()?;
+    let qq = b.i64()?;
+    let nickname = b.len_str()?;
+    let card = b.len_str()?;
+    // Gender and age.
+    b.bytes(8)?;
+    // Area.
+    b.len_bytes()?;
+    // Join time and last speak time.
+    b.bytes(8)?;
+    // Level.
+    b.len_bytes()?;
+    let role = match b.i32()? {
+        3 => MemberRole::Owner,
+        2 => MemberRole::Admin,
+        _ => MemberRole::Member,
+    };
+    let rv = MemberInfo {
+        grp: grp,
+        qq: qq,
+        nickname: nickname,
+        card: card,
+        role: role,
+    };
+    Ok(rv)
+}
+/// Parse the anonymous info given with anonymous group messages. The info
+/// itself is kept as the token, since CoolQ wants it back for bans.
+pub fn parse_anonymous(b64: &[u8]) -> Result<Anonymous, Error> {
+    let mut b = Unpacker::from_base64(b64)?;
+    let rv = Anonymous {
+        id: b.i64()?,
+        name: b.len_str()?,
+        token: String::from_utf8(b64.to_owned())?,
+    };
+    Ok(rv)
+}
+/// Parse the file info given on group uploads.
+pub fn parse_file_info(b64: &[u8]) -> Result<FileInfo, Error> {
+    let mut b = Unpacker::from_base64(b64)?;
+    let rv = FileInfo {
+        id: b.len_str()?,
+        name: b.len_str()?,
+        size: b.i64()?,
+    };
+    Ok(rv)
 }
 
 #[cfg(test)]
@@ -179,4 +303,68 @@ mod tests {
         assert_eq!(raw, composer.compose(&msg).unwrap());
         assert_eq!(msg, composer.decompose(&raw).unwrap());
     }
+    fn pack_str(s: &str, out: &mut Vec<u8>) {
+        let (buf, _, _) = GB18030.encode(s);
+        out.extend_from_slice(&[(buf.len() >> 8) as u8, buf.len() as u8]);
+        out.extend_from_slice(&buf);
+    }
+    #[test]
+    fn test_parse_member_info() {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        raw.extend_from_slice(&[0, 0, 0, 0, 0, 0, 1, 0]);
+        pack_str("企鹅", &mut raw);
+        pack_str("Liong", &mut raw);
+        raw.extend_from_slice(&[0; 8]);
+        pack_str("", &mut raw);
+        raw.extend_from_slice(&[0; 8]);
+        pack_str("", &mut raw);
+        raw.extend_from_slice(&[0, 0, 0, 2]);
+        let info = parse_member_info(base64::encode(&raw).as_bytes());
+        assert_eq!(info.unwrap(), MemberInfo {
+            grp: 2,
+            qq: 256,
+            nickname: "企鹅".to_owned(),
+            card: "Liong".to_owned(),
+            role: MemberRole::Admin,
+        });
+        raw.truncate(raw.len() - 1);
+        assert!(parse_member_info(base64::encode(&raw).as_bytes()).is_err());
+    }
+    #[test]
+    fn test_parse_anonymous() {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 5]);
+        pack_str("企鹅", &mut raw);
+        // Token.
+        raw.extend_from_slice(&[0, 2, 0xab, 0xcd]);
+        let b64 = base64::encode(&raw);
+        assert_eq!(parse_anonymous(b64.as_bytes()).unwrap(), Anonymous {
+            id: 5,
+            name: "企鹅".to_owned(),
+            token: b64.clone(),
+        });
+    }
+    #[test]
+    fn test_parse_file_info() {
+        let mut raw = Vec::new();
+        pack_str("/abc", &mut raw);
+        pack_str("笔记.txt", &mut raw);
+        raw.extend_from_slice(&[0, 0, 0, 0, 0, 0, 4, 0]);
+        // Bus ID.
+        raw.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 102]);
+        let info = parse_file_info(base64::encode(&raw).as_bytes());
+        assert_eq!(info.unwrap(), FileInfo {
+            id: "/abc".to_owned(),
+            name: "笔记.txt".to_owned(),
+            size: 1024,
+        });
+    }
+    #[test]
+    fn test_unpack_negative() {
+        let mut b = Unpacker::new(vec![0xff, 0xfe, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(b.i16().unwrap(), -2);
+        assert_eq!(b.i32().unwrap(), -1);
+        assert!(b.i16().is_err());
+    }
 }
@@ -0,0 +1,226 @@
+//! In-memory peripheral recording everything sent through it, for testing
+//! backends without a chat platform.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use failure::{err_msg, Error};
+use msg::Anonymous;
+use peripheral::{AdminError, MemberInfo, Peripheral, UserInfo};
+
+/// A composed message sent through the peripheral.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Sent {
+    Private(i64, String),
+    Group(i64, String),
+    Discuss(i64, String),
+}
+
+/// An action other than sending messages, taken through the peripheral.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Ban {
+        grp: i64,
+        qq: i64,
+        duration: i64,
+    },
+    BanAnonymous {
+        grp: i64,
+        token: String,
+        duration: i64,
+    },
+    Kick {
+        grp: i64,
+        qq: i64,
+        reject_rejoin: bool,
+    },
+    SetCard {
+        grp: i64,
+        qq: i64,
+        card: String,
+    },
+    SetAdmin {
+        grp: i64,
+        qq: i64,
+        admin: bool,
+    },
+    SetWholeBan {
+        grp: i64,
+        ban: bool,
+    },
+    SetSpecialTitle {
+        grp: i64,
+        qq: i64,
+        title: String,
+        duration: i64,
+    },
+    LeaveGroup {
+        grp: i64,
+        dispose: bool,
+    },
+    LeaveDiscuss {
+        discuss: i64,
+    },
+    AnswerFriendRequest {
+        flag: String,
+        approve: bool,
+        remark: String,
+    },
+    AnswerGroupRequest {
+        flag: String,
+        invited: bool,
+        approve: bool,
+        reason: String,
+    },
+}
+
+pub struct MemoryPeripheral {
+    sent: RefCell<Vec<Sent>>,
+    actions: RefCell<Vec<Action>>,
+    users: RefCell<BTreeMap<i64, UserInfo>>,
+    members: RefCell<BTreeMap<(i64, i64), MemberInfo>>,
+}
+impl Default for MemoryPeripheral {
+    fn default() -> MemoryPeripheral {
+        MemoryPeripheral::new()
+    }
+}
+impl MemoryPeripheral {
+    pub fn new() -> MemoryPeripheral {
+        MemoryPeripheral {
+            sent: RefCell::new(Vec::new()),
+            actions: RefCell::new(Vec::new()),
+            users: RefCell::new(BTreeMap::new()),
+            members: RefCell::new(BTreeMap::new()),
+        }
+    }
+    pub fn add_user(&self, info: UserInfo) {
+        self.users.borrow_mut().insert(info.qq, info);
+    }
+    pub fn add_member(&self, info: MemberInfo) {
+        self.members.borrow_mut().insert((info.grp, info.qq), info);
+    }
+    /// Take all the messages sent so far.
+    pub fn take_sent(&self) -> Vec<Sent> {
+        self.sent.borrow_mut().drain(..).collect()
+    }
+    fn act(&self, action: Action) -> Result<(), AdminError> {
+        self.actions.borrow_mut().push(action);
+        Ok(())
+    }
+    /// Take all the actions taken so far.
+    pub fn take_actions(&self) -> Vec<Action> {
+        self.actions.borrow_mut().drain(..).collect()
+    }
+}
+impl Peripheral for MemoryPeripheral {
+    fn name(&self) -> &'static str {
+        "peripheral.memory"
+    }
+    fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error> {
+        self.sent.borrow_mut().push(Sent::Private(qq, raw.to_owned()));
+        Ok(())
+    }
+    fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error> {
+        self.sent.borrow_mut().push(Sent::Group(grp, raw.to_owned()));
+        Ok(())
+    }
+    fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error> {
+        let sent = Sent::Discuss(discuss, raw.to_owned());
+        self.sent.borrow_mut().push(sent);
+        Ok(())
+    }
+    fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+        self.users.borrow().get(&qq)
+            .cloned()
+            .ok_or_else(|| err_msg(format!("unknown user {}", qq)))
+    }
+    fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+        self.members.borrow().get(&(grp, qq))
+            .cloned()
+            .ok_or_else(|| {
+                err_msg(format!("unknown member {} in group {}", qq, grp))
+            })
+    }
+    fn ban(&self, grp: i64, qq: i64, duration: i64)
+            -> Result<(), AdminError> {
+        self.act(Action::Ban {
+            grp: grp,
+            qq: qq,
+            duration: duration,
+        })
+    }
+    fn ban_anonymous(&self, grp: i64, anon: &Anonymous, duration: i64)
+            -> Result<(), AdminError> {
+        self.act(Action::BanAnonymous {
+            grp: grp,
+            token: anon.token.clone(),
+            duration: duration,
+        })
+    }
+    fn kick(&self, grp: i64, qq: i64, reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        self.act(Action::Kick {
+            grp: grp,
+            qq: qq,
+            reject_rejoin: reject_rejoin,
+        })
+    }
+    fn set_card(&self, grp: i64, qq: i64, card: &str)
+            -> Result<(), AdminError> {
+        self.act(Action::SetCard {
+            grp: grp,
+            qq: qq,
+            card: card.to_owned(),
+        })
+    }
+    fn set_admin(&self, grp: i64, qq: i64, admin: bool)
+            -> Result<(), AdminError> {
+        self.act(Action::SetAdmin {
+            grp: grp,
+            qq: qq,
+            admin: admin,
+        })
+    }
+    fn set_whole_ban(&self, grp: i64, ban: bool) -> Result<(), AdminError> {
+        self.act(Action::SetWholeBan {
+            grp: grp,
+            ban: ban,
+        })
+    }
+    fn set_special_title(&self, grp: i64, qq: i64, title: &str,
+                         duration: i64) -> Result<(), AdminError> {
+        self.act(Action::SetSpecialTitle {
+            grp: grp,
+            qq: qq,
+            title: title.to_owned(),
+            duration: duration,
+        })
+    }
+    fn leave_group(&self, grp: i64, dispose: bool)
+            -> Result<(), AdminError> {
+        self.act(Action::LeaveGroup {
+            grp: grp,
+            dispose: dispose,
+        })
+    }
+    fn leave_discuss(&self, discuss: i64) -> Result<(), AdminError> {
+        self.act(Action::LeaveDiscuss { discuss: discuss })
+    }
+    fn answer_friend_request(&self, flag: &str, approve: bool, remark: &str)
+            -> Result<(), AdminError> {
+        self.act(Action::AnswerFriendRequest {
+            flag: flag.to_owned(),
+            approve: approve,
+            remark: remark.to_owned(),
+        })
+    }
+    fn answer_group_request(&self, flag: &str, invited: bool,
+                            approve: bool, reason: &str)
+            -> Result<(), AdminError> {
+        self.act(Action::AnswerGroupRequest {
+            flag: flag.to_owned(),
+            invited: invited,
+            approve: approve,
+            reason: reason.to_owned(),
+        })
+    }
+}
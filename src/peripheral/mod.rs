@@ -1 +1,228 @@
+//! Chat platforms the bot runs on.
+//!
+//! A peripheral receives messages and lifecycle events from its platform and
+//! feeds them to a `Dispatcher`. In the other direction, backends act on the
+//! platform through the `Peripheral` trait.
+use std::error;
+use std::fmt;
+use failure::Error;
+use msg::Anonymous;
+
+pub mod console;
 pub mod coolq;
+pub mod memory;
+pub mod onebot;
+
+/// Role of a member in a group.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum MemberRole {
+    #[default]
+    Member,
+    Admin,
+    Owner,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UserInfo {
+    pub qq: i64,
+    pub nickname: String,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemberInfo {
+    pub grp: i64,
+    pub qq: i64,
+    pub nickname: String,
+    /// Group card, i.e. the alias in the group. Can be empty.
+    pub card: String,
+    pub role: MemberRole,
+}
+
+/// Lifecycle events of the bot, delivered by peripherals.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lifecycle {
+    Launch,
+    Shutdown,
+    Enable,
+    Disable,
+}
+
+/// A file uploaded to a group.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileInfo {
+    pub id: String,
+    pub name: String,
+    /// Size in bytes.
+    pub size: i64,
+}
+
+/// Notices and requests delivered by peripherals, other than messages.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    GroupUpload {
+        grp: i64,
+        qq: i64,
+        file: FileInfo,
+    },
+    /// `qq` is made an admin of the group if `set` is true, or is no longer
+    /// an admin otherwise.
+    AdminChange {
+        grp: i64,
+        qq: i64,
+        set: bool,
+    },
+    /// `qq` joined the group, approved or invited by `operator`.
+    MemberJoin {
+        grp: i64,
+        qq: i64,
+        operator: i64,
+        invited: bool,
+    },
+    /// `qq` left the group, or was kicked if there is an `operator`.
+    MemberLeave {
+        grp: i64,
+        qq: i64,
+        operator: Option<i64>,
+    },
+    /// `qq` is banned from speaking for `duration` seconds, or is unbanned
+    /// if `duration` is 0. The whole group is banned if `qq` is `None`.
+    Ban {
+        grp: i64,
+        qq: Option<i64>,
+        operator: i64,
+        duration: i64,
+    },
+    FriendAdded {
+        qq: i64,
+    },
+    /// `qq` wants to be a friend. `flag` identifies the request when it's
+    /// answered.
+    FriendRequest {
+        qq: i64,
+        comment: String,
+        flag: String,
+    },
+    /// `qq` wants to join the group, or invites the bot to the group if
+    /// `invited` is true.
+    GroupRequest {
+        grp: i64,
+        qq: i64,
+        comment: String,
+        flag: String,
+        invited: bool,
+    },
+}
+
+/// Why a group administration action failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AdminError {
+    /// The peripheral can't take the action.
+    Unsupported,
+    /// The arguments are rejected before reaching the platform.
+    Invalid(String),
+    /// The platform failed the action with an error code. It usually means
+    /// the bot isn't allowed to take the action.
+    Failed(i64),
+    /// The platform can't be reached.
+    Io(String),
+}
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminError::Unsupported => f.write_str("action is not supported"),
+            AdminError::Invalid(ref reason) => f.write_str(reason),
+            AdminError::Failed(code) => {
+                write!(f, "action failed with code {}", code)
+            },
+            AdminError::Io(ref reason) => {
+                write!(f, "platform is unreachable: {}", reason)
+            },
+        }
+    }
+}
+impl error::Error for AdminError {}
+impl From<Error> for AdminError {
+    fn from(err: Error) -> AdminError {
+        AdminError::Io(err.to_string())
+    }
+}
+
+pub trait Peripheral {
+    fn name(&self) -> &'static str;
+    /// Send a composed message to a user in private chat.
+    fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error>;
+    /// Send a composed message to a group.
+    fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error>;
+    /// Send a composed message to a discuss group.
+    fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error>;
+    fn user_info(&self, qq: i64) -> Result<UserInfo, Error>;
+    fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error>;
+
+    // Group administration. Durations are in seconds. Platforms without
+    // such things don't have to implement them. Use `group::GroupAdmin`
+    // for checked arguments.
+
+    /// Ban a member from speaking, or unban if `duration` is 0.
+    fn ban(&self, _grp: i64, _qq: i64, _duration: i64)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Ban an anonymous member from speaking.
+    fn ban_anonymous(&self, _grp: i64, _anon: &Anonymous, _duration: i64)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Kick a member, and reject their later requests to join if
+    /// `reject_rejoin` is true.
+    fn kick(&self, _grp: i64, _qq: i64, _reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Set the group card of a member. An empty card clears it.
+    fn set_card(&self, _grp: i64, _qq: i64, _card: &str)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Make a member an admin, or no longer an admin.
+    fn set_admin(&self, _grp: i64, _qq: i64, _admin: bool)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Ban all members but admins from speaking, or lift the ban.
+    fn set_whole_ban(&self, _grp: i64, _ban: bool) -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Give a member a special title, expiring after `duration`, or never if
+    /// `duration` is -1. An empty title clears it.
+    fn set_special_title(&self, _grp: i64, _qq: i64, _title: &str,
+                         _duration: i64) -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Leave the group, or dispose it if `dispose` is true and the bot is the
+    /// owner.
+    fn leave_group(&self, _grp: i64, _dispose: bool)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Leave the discuss group.
+    fn leave_discuss(&self, _discuss: i64) -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+
+    // Requests are answered by the flags in `Event::FriendRequest` and
+    // `Event::GroupRequest`.
+
+    /// Approve or reject a request to be a friend. The new friend is saved
+    /// as `remark` if it's not empty.
+    fn answer_friend_request(&self, _flag: &str, _approve: bool,
+                             _remark: &str) -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+    /// Approve or reject a request to join a group, or an invitation to a
+    /// group if `invited` is true. `reason` is told on rejection.
+    fn answer_group_request(&self, _flag: &str, _invited: bool,
+                            _approve: bool, _reason: &str)
+            -> Result<(), AdminError> {
+        Err(AdminError::Unsupported)
+    }
+}
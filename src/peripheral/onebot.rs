@@ -0,0 +1,701 @@
+//! OneBot (CQHTTP) v11 protocol support.
+//!
+//! `OneBotComposer` speaks the OneBot message format, where a message is an
+//! array of message segments like `[{"type":"text","data":{"text":"hi"}}]`.
+//! `OneBot` is a reverse-WebSocket server which any OneBot implementation can
+//! connect to, so the bot can run as a standalone process.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use serde_json::{self, Map, Value};
+use tungstenite::{self, Message, WebSocket};
+use failure::{err_msg, Error};
+use composer::Composer;
+use dispatcher::Dispatcher;
+use msg::{Anonymous, Msg, MsgIn, ExtBuilder, MsgBuilder};
+use peripheral::{AdminError, MemberInfo, MemberRole, Peripheral, UserInfo};
+use peripheral::coolq::decompose_cq;
+
+fn make_seg(ty: &str, data: Map<String, Value>) -> Value {
+    let mut seg = Map::new();
+    seg.insert("type".to_owned(), Value::String(ty.to_owned()));
+    seg.insert("data".to_owned(), Value::Object(data));
+    Value::Object(seg)
+}
+fn param_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(ref x) => Some(x.clone()),
+        // Numbers and booleans are sent by some implementations, but we only
+        // deal with text params.
+        x => Some(x.to_string()),
+    }
+}
+
+pub struct OneBotComposer;
+impl Default for OneBotComposer {
+    fn default() -> OneBotComposer {
+        OneBotComposer::new()
+    }
+}
+impl OneBotComposer {
+    pub fn new() -> OneBotComposer {
+        OneBotComposer
+    }
+    /// Convert a message into an array of message segments.
+    pub fn compose_segs(&self, msg: &Msg) -> Vec<Value> {
+        let mut rv = Vec::new();
+        self.compose_impl(msg, &mut rv);
+        rv
+    }
+    fn compose_impl(&self, msg: &Msg, out: &mut Vec<Value>) {
+        match msg {
+            Msg::Text(ref content) => {
+                if content.is_empty() {
+                    return
+                }
+                let mut data = Map::new();
+                data.insert("text".to_owned(), Value::String(content.clone()));
+                out.push(make_seg("text", data));
+            },
+            Msg::Ext { ref name, ref params } => {
+                let data = params.iter()
+                    .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+                    .collect();
+                out.push(make_seg(name, data));
+            },
+            Msg::Compound(ref segs) => {
+                for seg in segs {
+                    self.compose_impl(seg, out);
+                }
+            },
+        }
+    }
+    /// Convert message segments back into a message. A single segment object
+    /// is accepted as well as an array.
+    pub fn decompose_segs(&self, segs: &Value) -> Result<Msg, Error> {
+        let segs = match segs {
+            Value::Array(ref segs) => segs.iter().collect(),
+            Value::Object(_) => vec![segs],
+            _ => return Err(err_msg("message should be a segment array")),
+        };
+        let mut rv = MsgBuilder::new();
+        for seg in segs {
+            let ty = seg.get("type")
+                .and_then(Value::as_str)
+                .ok_or_else(|| err_msg("segment type is missing"))?;
+            let data = match seg.get("data") {
+                Some(Value::Object(ref data)) => Some(data),
+                None | Some(Value::Null) => None,
+                _ => return Err(err_msg("segment data should be an object")),
+            };
+            if ty == "text" {
+                let text = data
+                    .and_then(|data| data.get("text"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| err_msg("text segment has no text"))?;
+                rv.add_msg(Msg::Text(text.to_owned()));
+                continue
+            }
+            let mut ext = ExtBuilder::new(ty);
+            for (key, value) in data.into_iter().flat_map(|data| data.iter()) {
+                if let Some(value) = param_value(value) {
+                    ext.add_param(key, &value);
+                }
+            }
+            rv.add_msg(ext.build());
+        }
+        Ok(rv.build())
+    }
+}
+impl Composer for OneBotComposer {
+    fn name(&self) -> &'static str {
+        "composer.onebot"
+    }
+    fn compose(&self, msg: &Msg) -> Result<String, Error> {
+        let segs = Value::Array(self.compose_segs(msg));
+        Ok(serde_json::to_string(&segs)?)
+    }
+    fn decompose(&self, raw: &str) -> Result<Msg, Error> {
+        let segs = serde_json::from_str::<Value>(raw)?;
+        self.decompose_segs(&segs)
+    }
+}
+
+fn get_i64(value: &Value, key: &str) -> Result<i64, Error> {
+    value.get(key)
+        .and_then(Value::as_i64)
+        .ok_or_else(|| err_msg(format!("`{}` is missing in event", key)))
+}
+fn get_str<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key)
+        .and_then(Value::as_str)
+        .filter(|x| !x.is_empty())
+}
+
+/// Reverse-WebSocket server of the OneBot protocol. OneBot implementations
+/// connect to it, post events through the connection, and receive actions
+/// from it.
+pub struct OneBot {
+    listener: TcpListener,
+}
+impl OneBot {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<OneBot, Error> {
+        let rv = OneBot {
+            listener: TcpListener::bind(addr)?,
+        };
+        Ok(rv)
+    }
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve OneBot connections one after another, forever. The dispatcher is
+    /// enabled first.
+    pub fn serve(&self, dispatcher: &Dispatcher) -> Result<(), Error> {
+        dispatcher.enable();
+        loop {
+            if let Err(err) = self.serve_once(dispatcher) {
+                warn!("onebot connection broken: {}", err);
+            }
+        }
+    }
+    /// Accept a single OneBot connection and serve it until it's closed.
+    pub fn serve_once(&self, dispatcher: &Dispatcher) -> Result<(), Error> {
+        let (stream, _) = self.listener.accept()?;
+        let ws = tungstenite::server::accept(stream)
+            .map_err(|err| err_msg(format!("handshake failed: {}", err)))?;
+        let conn = Connection::new(ws);
+        while let Some(event) = conn.next_event()? {
+            if let Err(err) = dispatcher.poll_reload(&conn) {
+                error!("reload failed: {}", err);
+            }
+            // A bad event shouldn't take down the connection.
+            let msg_in = match make_msg_in(&event) {
+                Ok(Some(msg_in)) => msg_in,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("ignored malformed event {}: {}", event, err);
+                    continue
+                },
+            };
+            if let Err(err) = dispatcher.handle(&conn, &msg_in) {
+                warn!("unable to handle message from {}: {}", msg_in.qq(),
+                      err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A OneBot connection, through which backends can call OneBot actions.
+pub struct Connection<S: Read + Write> {
+    ws: RefCell<WebSocket<S>>,
+    echo: Cell<u64>,
+    /// Events received while waiting for action responses.
+    pending: RefCell<VecDeque<Value>>,
+}
+impl<S: Read + Write> Connection<S> {
+    pub fn new(ws: WebSocket<S>) -> Connection<S> {
+        Connection {
+            ws: RefCell::new(ws),
+            echo: Cell::new(0),
+            pending: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Read the next JSON frame. `None` is returned when the connection is
+    /// closed.
+    fn read(&self) -> Result<Option<Value>, Error> {
+        loop {
+            let frame = match self.ws.borrow_mut().read_message() {
+                Ok(Message::Text(frame)) => frame,
+                Ok(Message::Close(_)) |
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(None),
+                Ok(_) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            return Ok(Some(serde_json::from_str::<Value>(&frame)?))
+        }
+    }
+    /// Wait for the next event. Responses to actions nobody is waiting for
+    /// are checked and dropped.
+    pub fn next_event(&self) -> Result<Option<Value>, Error> {
+        if let Some(event) = self.pending.borrow_mut().pop_front() {
+            return Ok(Some(event))
+        }
+        while let Some(value) = self.read()? {
+            if value.get("post_type").is_some() {
+                return Ok(Some(value))
+            }
+            if let Err(err) = check_response(&value) {
+                warn!("onebot action failed: {}", err);
+            }
+        }
+        Ok(None)
+    }
+    /// Post an action without waiting for its response. The echo identifying
+    /// the action is returned.
+    pub fn post(&self, action: &str, params: Map<String, Value>)
+            -> Result<u64, Error> {
+        let echo = self.echo.get() + 1;
+        self.echo.set(echo);
+        let mut frame = Map::new();
+        frame.insert("action".to_owned(), Value::from(action));
+        frame.insert("params".to_owned(), Value::Object(params));
+        frame.insert("echo".to_owned(), Value::from(echo));
+        let frame = serde_json::to_string(&Value::Object(frame))?;
+        self.ws.borrow_mut().write_message(Message::Text(frame))?;
+        Ok(echo)
+    }
+    /// Wait for the response to the action identified by `echo`. Events
+    /// received meanwhile are kept for later.
+    fn wait(&self, action: &str, echo: u64) -> Result<Value, Error> {
+        while let Some(value) = self.read()? {
+            if value.get("post_type").is_some() {
+                self.pending.borrow_mut().push_back(value);
+            } else if value.get("echo").and_then(Value::as_u64) == Some(echo) {
+                return Ok(value)
+            }
+        }
+        Err(err_msg(format!("connection closed while calling `{}`", action)))
+    }
+    /// Call an action and wait for the data it returns.
+    pub fn call(&self, action: &str, params: Map<String, Value>)
+            -> Result<Value, Error> {
+        let echo = self.post(action, params)?;
+        let value = self.wait(action, echo)?;
+        check_response(&value)?;
+        Ok(value.get("data").cloned().unwrap_or(Value::Null))
+    }
+    /// Call an administrative action, telling apart the ways it can fail.
+    fn admin(&self, action: &str, params: Map<String, Value>)
+            -> Result<(), AdminError> {
+        let echo = self.post(action, params)?;
+        let value = self.wait(action, echo)?;
+        match value.get("retcode").and_then(Value::as_i64) {
+            // 1 means the action is queued to be taken asynchronously.
+            Some(0) | Some(1) => Ok(()),
+            Some(100) => {
+                let reason = format!("invalid params for `{}`", action);
+                Err(AdminError::Invalid(reason))
+            },
+            Some(retcode) => Err(AdminError::Failed(retcode)),
+            None => {
+                let reason = format!("unexpected response {}", value);
+                Err(AdminError::Io(reason))
+            },
+        }
+    }
+}
+impl<S: Read + Write> Peripheral for Connection<S> {
+    fn name(&self) -> &'static str {
+        "peripheral.onebot"
+    }
+    fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error> {
+        let mut params = Map::new();
+        params.insert("user_id".to_owned(), Value::from(qq));
+        params.insert("message".to_owned(), make_message(raw));
+        self.post("send_private_msg", params).map(|_| ())
+    }
+    fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error> {
+        let mut params = Map::new();
+        params.insert("group_id".to_owned(), Value::from(grp));
+        params.insert("message".to_owned(), make_message(raw));
+        self.post("send_group_msg", params).map(|_| ())
+    }
+    fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error> {
+        let mut params = Map::new();
+        params.insert("discuss_id".to_owned(), Value::from(discuss));
+        params.insert("message".to_owned(), make_message(raw));
+        self.post("send_discuss_msg", params).map(|_| ())
+    }
+    fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+        let mut params = Map::new();
+        params.insert("user_id".to_owned(), Value::from(qq));
+        let data = self.call("get_stranger_info", params)?;
+        let rv = UserInfo {
+            qq: qq,
+            nickname: get_str(&data, "nickname").unwrap_or("").to_owned(),
+        };
+        Ok(rv)
+    }
+    fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+        let mut params = Map::new();
+        params.insert("group_id".to_owned(), Value::from(grp));
+        params.insert("user_id".to_owned(), Value::from(qq));
+        let data = self.call("get_group_member_info", params)?;
+        let role = match get_str(&data, "role") {
+            Some("owner") => MemberRole::Owner,
+            Some("admin") => MemberRole::Admin,
+            _ => MemberRole::Member,
+        };
+        let rv = MemberInfo {
+            grp: grp,
+            qq: qq,
+            nickname: get_str(&data, "nickname").unwrap_or("").to_owned(),
+            card: get_str(&data, "card").unwrap_or("").to_owned(),
+            role: role,
+        };
+        Ok(rv)
+    }
+    fn ban(&self, grp: i64, qq: i64, duration: i64)
+            -> Result<(), AdminError> {
+        let mut params = member_params(grp, qq);
+        params.insert("duration".to_owned(), Value::from(duration));
+        self.admin("set_group_ban", params)
+    }
+    fn ban_anonymous(&self, grp: i64, anon: &Anonymous, duration: i64)
+            -> Result<(), AdminError> {
+        let mut params = Map::new();
+        params.insert("group_id".to_owned(), Value::from(grp));
+        params.insert("anonymous_flag".to_owned(),
+                      Value::String(anon.token.clone()));
+        params.insert("duration".to_owned(), Value::from(duration));
+        self.admin("set_group_anonymous_ban", params)
+    }
+    fn kick(&self, grp: i64, qq: i64, reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        let mut params = member_params(grp, qq);
+        params.insert("reject_add_request".to_owned(),
+                      Value::from(reject_rejoin));
+        self.admin("set_group_kick", params)
+    }
+    fn set_card(&self, grp: i64, qq: i64, card: &str)
+            -> Result<(), AdminError> {
+        let mut params = member_params(grp, qq);
+        params.insert("card".to_owned(), Value::from(card));
+        self.admin("set_group_card", params)
+    }
+    fn set_admin(&self, grp: i64, qq: i64, admin: bool)
+            -> Result<(), AdminError> {
+        let mut params = member_params(grp, qq);
+        params.insert("enable".to_owned(), Value::from(admin));
+        self.admin("set_group_admin", params)
+    }
+    fn set_whole_ban(&self, grp: i64, ban: bool) -> Result<(), AdminError> {
+        let mut params = Map::new();
+        params.insert("group_id".to_owned(), Value::from(grp));
+        params.insert("enable".to_owned(), Value::from(ban));
+        self.admin("set_group_whole_ban", params)
+    }
+    fn set_special_title(&self, grp: i64, qq: i64, title: &str,
+                         duration: i64) -> Result<(), AdminError> {
+        let mut params = member_params(grp, qq);
+        params.insert("special_title".to_owned(), Value::from(title));
+        params.insert("duration".to_owned(), Value::from(duration));
+        self.admin("set_group_special_title", params)
+    }
+    fn leave_group(&self, grp: i64, dispose: bool)
+            -> Result<(), AdminError> {
+        let mut params = Map::new();
+        params.insert("group_id".to_owned(), Value::from(grp));
+        params.insert("is_dismiss".to_owned(), Value::from(dispose));
+        self.admin("set_group_leave", params)
+    }
+    fn leave_discuss(&self, discuss: i64) -> Result<(), AdminError> {
+        let mut params = Map::new();
+        params.insert("discuss_id".to_owned(), Value::from(discuss));
+        self.admin("set_discuss_leave", params)
+    }
+}
+
+fn member_params(grp: i64, qq: i64) -> Map<String, Value> {
+    let mut params = Map::new();
+    params.insert("group_id".to_owned(), Value::from(grp));
+    params.insert("user_id".to_owned(), Value::from(qq));
+    params
+}
+
+fn check_response(value: &Value) -> Result<(), Error> {
+    match value.get("retcode").and_then(Value::as_i64) {
+        Some(0) => Ok(()),
+        Some(retcode) => {
+            Err(err_msg(format!("action {} failed with retcode {}",
+                                value.get("echo").unwrap_or(&Value::Null),
+                                retcode)))
+        },
+        None => Err(err_msg(format!("unexpected response {}", value))),
+    }
+}
+/// Make the `message` param of a sending action.
+fn make_message(raw: &str) -> Value {
+    // OneBot accepts both segment arrays and CQ-code strings, so we don't
+    // care which composer is in use.
+    match serde_json::from_str::<Value>(raw) {
+        Ok(segs @ Value::Array(_)) => segs,
+        _ => Value::String(raw.to_owned()),
+    }
+}
+
+/// Get the identity of the anonymous sender of a group message event.
+fn get_anonymous(event: &Value) -> Result<Option<Anonymous>, Error> {
+    let anon = match event.get("anonymous") {
+        Some(anon @ Value::Object(_)) => anon,
+        _ => return Ok(None),
+    };
+    let rv = Anonymous {
+        id: get_i64(anon, "id")?,
+        name: get_str(anon, "name").unwrap_or("").to_owned(),
+        token: get_str(anon, "flag")
+            .ok_or_else(|| err_msg("`flag` is missing in event"))?
+            .to_owned(),
+    };
+    Ok(Some(rv))
+}
+/// Make an incoming message from a message event. `None` is returned for
+/// other kinds of events. Messages can be posted either as segment arrays or
+/// as strings in CQ codes.
+pub fn make_msg_in(event: &Value) -> Result<Option<MsgIn>, Error> {
+    if get_str(event, "post_type") != Some("message") {
+        return Ok(None)
+    }
+    let content = match event.get("message") {
+        // File params are kept as they are, as with segment arrays.
+        Some(Value::String(ref raw)) => decompose_cq(raw, None)?,
+        Some(segs) => OneBotComposer::new().decompose_segs(segs)?,
+        None => return Err(err_msg("`message` is missing in event")),
+    };
+    let qq = get_i64(event, "user_id")?;
+    let sender = event.get("sender").unwrap_or(&Value::Null);
+    let alias = get_str(sender, "nickname")
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| qq.to_string());
+    let rv = match get_str(event, "message_type") {
+        Some("private") => MsgIn::Private {
+            qq: qq,
+            alias: alias,
+            content: content,
+        },
+        Some("group") => MsgIn::Group {
+            grp: get_i64(event, "group_id")?,
+            qq: qq,
+            grp_alias: get_str(sender, "card")
+                .map(ToOwned::to_owned)
+                .unwrap_or_else(|| alias.clone()),
+            alias: alias,
+            content: content,
+            anon: get_anonymous(event)?,
+        },
+        Some("discuss") => MsgIn::Discuss {
+            discuss: get_i64(event, "discuss_id")?,
+            qq: qq,
+            alias: alias,
+            content: content,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(rv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use backend::{Backend, BackendMetadata, Context, Outcome};
+    use msg::*;
+
+    #[test]
+    fn test_simple() {
+        let composer = OneBotComposer::new();
+        let ext = ExtBuilder::new("x").with_param("y", "123")
+                                      .build();
+        let raw = concat!(r#"[{"data":{"text":"123"},"type":"text"},"#,
+                          r#"{"data":{"y":"123"},"type":"x"}]"#);
+        let msg = msg!["123", ext];
+        assert_eq!(raw, composer.compose(&msg).unwrap());
+        assert_eq!(msg, composer.decompose(raw).unwrap());
+    }
+    #[test]
+    fn test_escape() {
+        let composer = OneBotComposer::new();
+        let ext = ExtBuilder::new("x").with_param("y", "&[],\"")
+                                      .build();
+        let raw = concat!(r#"[{"data":{"text":",&[]"},"type":"text"},"#,
+                          r#"{"data":{"y":"&[],\""},"type":"x"}]"#);
+        let msg = msg![",&[]", ext];
+        assert_eq!(raw, composer.compose(&msg).unwrap());
+        assert_eq!(msg, composer.decompose(raw).unwrap());
+    }
+    #[test]
+    fn test_shortcut() {
+        let composer = OneBotComposer::new();
+        let raw = concat!(r#"[{"data":{"qq":"123"},"type":"at"},"#,
+                          r#"{"data":{"file":"1.jpg"},"type":"image"}]"#);
+        let msg = msg![at(123), image("1.jpg")];
+        assert_eq!(raw, composer.compose(&msg).unwrap());
+        assert_eq!(msg, composer.decompose(raw).unwrap());
+    }
+    #[test]
+    fn test_decompose_loose() {
+        let composer = OneBotComposer::new();
+        let raw = r#"[
+            {"type":"text","data":{"text":"1"}},
+            {"type":"text","data":{"text":"2"}},
+            {"type":"at","data":{"qq":123,"name":null}},
+            {"type":"shake"}
+        ]"#;
+        let msg = msg!["12", at(123), ExtBuilder::new("shake").build()];
+        assert_eq!(msg, composer.decompose(raw).unwrap());
+        assert_eq!(at(123),
+                   composer.decompose(r#"{"type":"at","data":{"qq":"123"}}"#)
+                       .unwrap());
+        assert!(composer.decompose(r#""text""#).is_err());
+        assert!(composer.decompose(r#"[{"data":{}}]"#).is_err());
+    }
+
+    struct Echo;
+    impl Backend for Echo {
+        fn metadata(&self) -> BackendMetadata {
+            BackendMetadata {
+                identity: "test.echo",
+                ..Default::default()
+            }
+        }
+        fn preview(&self, _: &MsgIn) -> bool {
+            true
+        }
+        fn process(&self, ctx: &Context, msg_in: &MsgIn)
+                -> Result<Outcome, Error> {
+            if let MsgIn::Group { grp, qq, .. } = msg_in {
+                let info = ctx.member_info(*grp, *qq)?;
+                let reply = format!("{} {:?}", info.card, info.role);
+                return Ok(Outcome::Reply(::msg::text(&reply)))
+            }
+            Ok(Outcome::Reply(msg_in.content().clone()))
+        }
+    }
+    /// Fake OneBot implementation posting events and collecting actions.
+    /// Member info queries are answered with a fixed member.
+    fn fake_onebot(addr: SocketAddr, events: Vec<&'static str>)
+            -> thread::JoinHandle<Vec<Value>> {
+        ::logger::init_test();
+        thread::spawn(move || {
+            let url = format!("ws://{}/", addr);
+            let (mut ws, _) = tungstenite::client::connect(url.as_str())
+                .unwrap();
+            let mut actions = Vec::new();
+            for event in events {
+                ws.write_message(Message::Text(event.to_owned())).unwrap();
+                // Only well-formed message events, which always have
+                // senders in the tests, are answered.
+                if !event.contains("\"sender\"") {
+                    continue
+                }
+                loop {
+                    let action: Value = match ws.read_message().unwrap() {
+                        Message::Text(x) => serde_json::from_str(&x).unwrap(),
+                        x => panic!("unexpected frame: {:?}", x),
+                    };
+                    let name = action["action"].as_str().unwrap().to_owned();
+                    if name == "get_group_member_info" {
+                        let resp = format!(r#"{{"status":"ok","retcode":0,
+                            "data":{{"card":"Al","role":"admin"}},
+                            "echo":{}}}"#, action["echo"]);
+                        ws.write_message(Message::Text(resp)).unwrap();
+                    }
+                    actions.push(action);
+                    if name.starts_with("send_") {
+                        break
+                    }
+                }
+            }
+            ws.close(None).unwrap();
+            // Wait for the server to acknowledge the close.
+            while ws.read_message().is_ok() {}
+            actions
+        })
+    }
+    #[test]
+    fn test_reverse_websocket() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_composer(OneBotComposer::new())
+            .use_backend(Echo, 0);
+        dispatcher.enable();
+        let onebot = OneBot::bind("127.0.0.1:0").unwrap();
+        let client = fake_onebot(onebot.local_addr().unwrap(), vec![
+            r#"{"post_type":"meta_event","meta_event_type":"heartbeat"}"#,
+            r#"{"post_type":"message","message_type":"private","user_id":1,
+                "message":[{"type":"text","data":{"text":"hi"}}],
+                "sender":{"nickname":"Alice"}}"#,
+            r#"{"post_type":"message","message_type":"group","user_id":1,
+                "group_id":2,"message":[{"type":"at","data":{"qq":"3"}}],
+                "sender":{"nickname":"Alice","card":""}}"#,
+        ]);
+        onebot.serve_once(&dispatcher).unwrap();
+        let actions = client.join().unwrap();
+        let expected: Value = serde_json::from_str(r#"[
+            {"action":"send_private_msg","echo":1,"params":{"user_id":1,
+             "message":[{"type":"text","data":{"text":"hi"}}]}},
+            {"action":"get_group_member_info","echo":2,
+             "params":{"group_id":2,"user_id":1}},
+            {"action":"send_group_msg","echo":3,"params":{"group_id":2,
+             "message":[{"type":"text","data":{"text":"Al Admin"}}]}}
+        ]"#).unwrap();
+        assert_eq!(Value::Array(actions), expected);
+    }
+    #[test]
+    fn test_string_message() {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .use_composer(OneBotComposer::new())
+            .use_backend(Echo, 0);
+        dispatcher.enable();
+        let onebot = OneBot::bind("127.0.0.1:0").unwrap();
+        let client = fake_onebot(onebot.local_addr().unwrap(), vec![
+            // Malformed events are skipped without breaking the connection.
+            r#"{"post_type":"message","message_type":"private"}"#,
+            r#"{"post_type":"message","message_type":"private","user_id":1,
+                "message":"hi[CQ:at,qq=3]","sender":{"nickname":"Alice"}}"#,
+        ]);
+        onebot.serve_once(&dispatcher).unwrap();
+        let actions = client.join().unwrap();
+        let expected: Value = serde_json::from_str(r#"[
+            {"action":"send_private_msg","echo":1,"params":{"user_id":1,
+             "message":[{"type":"text","data":{"text":"hi"}},
+                        {"type":"at","data":{"qq":"3"}}]}}
+        ]"#).unwrap();
+        assert_eq!(Value::Array(actions), expected);
+    }
+    #[test]
+    fn test_make_msg_in() {
+        let event = serde_json::from_str(r#"{"post_type":"message",
+            "message_type":"group","user_id":1,"group_id":2,
+            "message":"hi[CQ:at,qq=3]",
+            "sender":{"nickname":"Alice","card":"Al"}}"#).unwrap();
+        match make_msg_in(&event).unwrap() {
+            Some(MsgIn::Group { grp, qq, alias, grp_alias, content, .. }) => {
+                assert_eq!((grp, qq), (2, 1));
+                assert_eq!((alias.as_str(), grp_alias.as_str()),
+                           ("Alice", "Al"));
+                assert_eq!(content, msg!["hi", at(3)]);
+            },
+            x => panic!("unexpected message: {:?}", x),
+        }
+        let event = serde_json::from_str(r#"{"post_type":"message",
+            "message_type":"group","user_id":80000000,"group_id":2,
+            "message":"hi","sender":{},
+            "anonymous":{"id":5,"name":"Penguin","flag":"abc"}}"#).unwrap();
+        let msg_in = make_msg_in(&event).unwrap().unwrap();
+        assert_eq!(msg_in.anon(), Some(&Anonymous {
+            id: 5,
+            name: "Penguin".to_owned(),
+            token: "abc".to_owned(),
+        }));
+        let event = serde_json::from_str(r#"{"post_type":"message",
+            "message_type":"discuss","user_id":1,"discuss_id":4,
+            "message":"hi","sender":{"nickname":"Alice"}}"#).unwrap();
+        assert_eq!(make_msg_in(&event).unwrap(),
+                   Some(MsgIn::Discuss {
+                       discuss: 4,
+                       qq: 1,
+                       alias: "Alice".to_owned(),
+                       content: text("hi"),
+                   }));
+        let event = serde_json::from_str(r#"{"post_type":"notice"}"#)
+            .unwrap();
+        assert!(make_msg_in(&event).unwrap().is_none());
+    }
+}
@@ -0,0 +1,521 @@
+//! Policy on requests to be a friend of the bot, to join groups the bot
+//! administrates, and invitations of the bot to groups. Each kind of request
+//! is approved, rejected, forwarded to bot owners and admins to be answered
+//! in chat, or ignored and left to other backends, as is set in config:
+//!
+//! ```toml
+//! [backends."liongbot.requests".settings]
+//! friend = "approve"
+//! join = "ask"
+//! invite = "reject"
+//! keywords = ["penguin"]
+//! ```
+//!
+//! Requests from users on the allowlist, invitations to groups on the
+//! allowlist, and join requests mentioning any of the `keywords` are always
+//! approved. Requests from blacklisted users are always rejected.
+//!
+//! Requests are answered with the commands below, which are served by the
+//! admin backend along with other `#` commands:
+//!
+//! * `#requests` - List requests waiting for answers.
+//! * `#approve <id>` - Approve a request.
+//! * `#reject <id> [reason]` - Reject a request.
+//! * `#allow <user|group> <id>` - Put a user or a group on the allowlist.
+//! * `#disallow <user|group> <id>` - Take a user or a group off the
+//!   allowlist.
+use std::collections::BTreeMap;
+use std::fmt;
+use failure::Error;
+use serde_json::Value;
+use {Backend, MsgIn};
+use backend::{BackendMetadata, Context, Outcome};
+use command::{ArgKind, Args, Command};
+use config;
+use msg::text;
+use peripheral::{AdminError, Event, Peripheral};
+use role::{Role, Roles};
+use storage::{Scope, Store};
+
+pub const IDENTITY: &'static str = "liongbot.requests";
+const PENDING: &'static str = "pending";
+const ALLOWED: &'static str = "allowed";
+
+/// What to do with a kind of request.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all="snake_case")]
+pub enum Policy {
+    Approve,
+    Reject,
+    /// Forward to bot owners and admins, to be answered in chat.
+    Ask,
+    /// Leave it to other backends.
+    Ignore,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub friend: Policy,
+    pub join: Policy,
+    pub invite: Policy,
+    /// Join requests with any of the keywords in comment are approved.
+    pub keywords: Vec<String>,
+}
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            friend: Policy::Ask,
+            join: Policy::Ask,
+            invite: Policy::Ask,
+            keywords: Vec::new(),
+        }
+    }
+}
+
+/// A request to be answered.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag="kind", rename_all="snake_case")]
+pub enum Request {
+    Friend {
+        qq: i64,
+        comment: String,
+        flag: String,
+    },
+    Join {
+        grp: i64,
+        qq: i64,
+        comment: String,
+        flag: String,
+    },
+    Invite {
+        grp: i64,
+        qq: i64,
+        comment: String,
+        flag: String,
+    },
+}
+impl Request {
+    pub fn from_event(event: &Event) -> Option<Request> {
+        let rv = match event {
+            Event::FriendRequest { qq, ref comment, ref flag } => {
+                Request::Friend {
+                    qq: *qq,
+                    comment: comment.clone(),
+                    flag: flag.clone(),
+                }
+            },
+            Event::GroupRequest { grp, qq, ref comment, ref flag, invited } => {
+                if *invited {
+                    Request::Invite {
+                        grp: *grp,
+                        qq: *qq,
+                        comment: comment.clone(),
+                        flag: flag.clone(),
+                    }
+                } else {
+                    Request::Join {
+                        grp: *grp,
+                        qq: *qq,
+                        comment: comment.clone(),
+                        flag: flag.clone(),
+                    }
+                }
+            },
+            _ => return None,
+        };
+        Some(rv)
+    }
+    /// The user making the request.
+    pub fn qq(&self) -> i64 {
+        match self {
+            Request::Friend { qq, .. } |
+            Request::Join { qq, .. } |
+            Request::Invite { qq, .. } => *qq,
+        }
+    }
+    pub fn comment(&self) -> &str {
+        match self {
+            Request::Friend { ref comment, .. } |
+            Request::Join { ref comment, .. } |
+            Request::Invite { ref comment, .. } => comment,
+        }
+    }
+    /// Approve or reject the request. `reason` is told on rejection.
+    pub fn answer(&self, peripheral: &Peripheral, approve: bool,
+                  reason: &str) -> Result<(), AdminError> {
+        match self {
+            Request::Friend { ref flag, .. } => {
+                peripheral.answer_friend_request(flag, approve, "")
+            },
+            Request::Join { ref flag, .. } => {
+                peripheral.answer_group_request(flag, false, approve, reason)
+            },
+            Request::Invite { ref flag, .. } => {
+                peripheral.answer_group_request(flag, true, approve, reason)
+            },
+        }
+    }
+}
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Friend { qq, .. } => {
+                write!(f, "{} wants to be a friend", qq)?
+            },
+            Request::Join { grp, qq, .. } => {
+                write!(f, "{} wants to join group {}", qq, grp)?
+            },
+            Request::Invite { grp, qq, .. } => {
+                write!(f, "{} invites the bot to group {}", qq, grp)?
+            },
+        }
+        if !self.comment().is_empty() {
+            write!(f, ": {}", self.comment())?;
+        }
+        Ok(())
+    }
+}
+
+/// Requests waiting for answers, by ids given in order.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Pending {
+    next_id: i64,
+    requests: BTreeMap<i64, Request>,
+}
+impl Pending {
+    fn load(store: &Store) -> Result<Pending, Error> {
+        Ok(store.get_json(Scope::Global, PENDING)?.unwrap_or_default())
+    }
+    fn save(&self, store: &Store) -> Result<(), Error> {
+        store.set_json(Scope::Global, PENDING, self)
+    }
+    fn add(&mut self, request: Request) -> i64 {
+        self.next_id += 1;
+        self.requests.insert(self.next_id, request);
+        self.next_id
+    }
+}
+
+/// Storage of the request backend, whichever backend serves the commands.
+fn store<'a>(ctx: &Context<'a>) -> Store<'a> {
+    ctx.store().with_owner(IDENTITY)
+}
+fn is_allowed(store: &Store, scope: Scope) -> Result<bool, Error> {
+    Ok(store.get(scope, ALLOWED)?.is_some())
+}
+/// Parse the scope of an allowlist entry, with a label to tell users.
+fn allowlist_scope(args: &Args) -> Option<(Scope, String)> {
+    let id = args.int("id").unwrap();
+    match args.str("scope") {
+        Some("user") => Some((Scope::User(id), format!("User {}", id))),
+        Some("group") => Some((Scope::Group(id), format!("Group {}", id))),
+        _ => None,
+    }
+}
+fn not_allowlisted() -> Outcome {
+    Outcome::Fail(text("Only users and groups can be on the allowlist."))
+}
+/// Answer the pending request of `id`. It stays pending if the answer
+/// fails.
+fn answer(ctx: &Context, id: i64, approve: bool, reason: &str)
+        -> Result<Outcome, Error> {
+    let store = store(ctx);
+    let mut pending = Pending::load(&store)?;
+    let request = match pending.requests.get(&id) {
+        Some(request) => request.clone(),
+        None => {
+            let reply = format!("There is no request {}.", id);
+            return Ok(Outcome::Fail(text(&reply)))
+        },
+    };
+    if let Err(err) = request.answer(ctx.peripheral(), approve, reason) {
+        let reply = format!("Unable to answer request {}: {}", id, err);
+        return Ok(Outcome::Fail(text(&reply)))
+    }
+    pending.requests.remove(&id);
+    pending.save(&store)?;
+    let verb = if approve { "Approved" } else { "Rejected" };
+    Ok(Outcome::Reply(text(&format!("{}: {}.", verb, request))))
+}
+
+/// Commands to answer requests by hand, to be served with a `#` prefix.
+pub fn commands() -> Vec<Command> {
+    let requests = Command::new("requests", |ctx, _, _| {
+        let pending = Pending::load(&store(ctx))?;
+        if pending.requests.is_empty() {
+            return Ok(Outcome::Reply(text("No requests are waiting.")))
+        }
+        let mut report = String::from("Requests:");
+        for (id, request) in pending.requests.iter() {
+            report.push_str(&format!("\n{}: {}", id, request));
+        }
+        Ok(Outcome::Reply(text(&report)))
+    })
+        .with_description("List requests waiting for answers.")
+        .with_role(Role::BotAdmin);
+    let approve = Command::new("approve", |ctx, _, args| {
+        answer(ctx, args.int("id").unwrap(), true, "")
+    })
+        .with_description("Approve a request.")
+        .with_arg("id", ArgKind::Int)
+        .with_role(Role::BotAdmin);
+    let reject = Command::new("reject", |ctx, _, args| {
+        let reason = args.str("reason").unwrap_or("");
+        answer(ctx, args.int("id").unwrap(), false, reason)
+    })
+        .with_description("Reject a request.")
+        .with_arg("id", ArgKind::Int)
+        .with_optional_arg("reason", ArgKind::Str)
+        .with_role(Role::BotAdmin);
+    let allow = Command::new("allow", |ctx, _, args| {
+        let (scope, label) = match allowlist_scope(args) {
+            Some(x) => x,
+            None => return Ok(not_allowlisted()),
+        };
+        store(ctx).set_json(scope, ALLOWED, &true)?;
+        let reply = format!("{} is on the allowlist.", label);
+        Ok(Outcome::Reply(text(&reply)))
+    })
+        .with_description("Always approve requests of a user or a group.")
+        .with_arg("scope", ArgKind::Word)
+        .with_arg("id", ArgKind::Int)
+        .with_role(Role::BotAdmin);
+    let disallow = Command::new("disallow", |ctx, _, args| {
+        let (scope, label) = match allowlist_scope(args) {
+            Some(x) => x,
+            None => return Ok(not_allowlisted()),
+        };
+        store(ctx).remove(scope, ALLOWED)?;
+        let reply = format!("{} is off the allowlist.", label);
+        Ok(Outcome::Reply(text(&reply)))
+    })
+        .with_description("Take a user or a group off the allowlist.")
+        .with_arg("scope", ArgKind::Word)
+        .with_arg("id", ArgKind::Int)
+        .with_role(Role::BotAdmin);
+    vec![requests, approve, reject, allow, disallow]
+}
+
+/// Backend answering requests by policy. It doesn't take any message, the
+/// commands are in `commands`.
+#[derive(Default)]
+pub struct RequestBackend {
+    settings: Settings,
+}
+impl RequestBackend {
+    pub fn new() -> RequestBackend {
+        RequestBackend::default()
+    }
+    fn decide(&self, ctx: &Context, request: &Request)
+            -> Result<Policy, Error> {
+        let no_roles = Roles::new();
+        let roles = ctx.roles().unwrap_or(&no_roles);
+        if roles.bot_role(ctx, request.qq())? == Role::Blacklisted {
+            return Ok(Policy::Reject)
+        }
+        let store = ctx.store();
+        if is_allowed(&store, Scope::User(request.qq()))? {
+            return Ok(Policy::Approve)
+        }
+        let rv = match request {
+            Request::Friend { .. } => self.settings.friend,
+            Request::Join { ref comment, .. } => {
+                let hit = self.settings.keywords.iter()
+                    .any(|keyword| comment.contains(keyword.as_str()));
+                if hit { Policy::Approve } else { self.settings.join }
+            },
+            Request::Invite { grp, .. } => {
+                if is_allowed(&store, Scope::Group(*grp))? {
+                    Policy::Approve
+                } else {
+                    self.settings.invite
+                }
+            },
+        };
+        Ok(rv)
+    }
+    /// Forward the request to bot owners and admins. Nothing is done if
+    /// there is nobody to ask.
+    fn ask(&self, ctx: &Context, request: Request) -> Result<bool, Error> {
+        let no_roles = Roles::new();
+        let staff = ctx.roles().unwrap_or(&no_roles).bot_admins(ctx)?;
+        if staff.is_empty() {
+            warn!("nobody to ask about request: {}", request);
+            return Ok(false)
+        }
+        let store = ctx.store();
+        let mut pending = Pending::load(&store)?;
+        let notice = format!("Request {}: {}.", pending.next_id + 1, request);
+        let id = pending.add(request);
+        pending.save(&store)?;
+        let notice = text(&format!("{}\nAnswer with #approve {} or \
+                                    #reject {}.", notice, id, id));
+        for qq in staff {
+            if let Err(err) = ctx.send_priv(qq, &notice) {
+                warn!("unable to forward request {} to {}: {}", id, qq, err);
+            }
+        }
+        Ok(true)
+    }
+}
+impl Backend for RequestBackend {
+    fn metadata(&self) -> BackendMetadata {
+        BackendMetadata {
+            identity: IDENTITY,
+            name: "Requests",
+            author: "PENGUINLIONG",
+            description: "Answer friend and group join requests.",
+            ..Default::default()
+        }
+    }
+    fn configure(&mut self, settings: &Value) -> Result<(), Error> {
+        self.settings = config::parse_settings(settings)?;
+        Ok(())
+    }
+    fn preview(&self, _msg_in: &MsgIn) -> bool {
+        false
+    }
+    fn process(&self, _ctx: &Context, _msg_in: &MsgIn)
+            -> Result<Outcome, Error> {
+        Ok(Outcome::Pass)
+    }
+    fn on_event(&self, ctx: &Context, event: &Event) -> Result<bool, Error> {
+        let request = match Request::from_event(event) {
+            Some(request) => request,
+            None => return Ok(false),
+        };
+        match self.decide(ctx, &request)? {
+            Policy::Approve => request.answer(ctx.peripheral(), true, "")?,
+            Policy::Reject => request.answer(ctx.peripheral(), false, "")?,
+            Policy::Ask => return self.ask(ctx, request),
+            Policy::Ignore => return Ok(false),
+        }
+        info!("answered request by policy: {}", request);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use admin::admin_backend;
+    use dispatcher::Dispatcher;
+    use msg::{Msg, MsgIn};
+    use peripheral::memory::{Action, MemoryPeripheral, Sent};
+
+    fn make_dispatcher() -> Dispatcher {
+        let mut backend = RequestBackend::new();
+        backend.configure(&serde_json::from_str(r#"{
+            "friend": "approve",
+            "invite": "reject",
+            "keywords": ["penguin"]
+        }"#).unwrap()).unwrap();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable();
+        dispatcher
+            .use_roles(Roles::new().with_owner(1))
+            .use_backend(admin_backend(), 1000)
+            .use_backend(backend, 100);
+        dispatcher
+    }
+    fn make_msg_in(qq: i64, content: &str) -> MsgIn {
+        MsgIn::Private {
+            qq: qq,
+            alias: qq.to_string(),
+            content: text(content),
+        }
+    }
+    fn friend(qq: i64, flag: &str) -> Event {
+        Event::FriendRequest {
+            qq: qq,
+            comment: String::new(),
+            flag: flag.to_owned(),
+        }
+    }
+    fn grp_req(qq: i64, comment: &str, flag: &str, invited: bool) -> Event {
+        Event::GroupRequest {
+            grp: 20,
+            qq: qq,
+            comment: comment.to_owned(),
+            flag: flag.to_owned(),
+            invited: invited,
+        }
+    }
+    fn answered(flag: &str, invited: bool, approve: bool) -> Action {
+        Action::AnswerGroupRequest {
+            flag: flag.to_owned(),
+            invited: invited,
+            approve: approve,
+            reason: String::new(),
+        }
+    }
+    #[test]
+    fn test_policy() {
+        let peri = MemoryPeripheral::new();
+        let dispatcher = make_dispatcher();
+        Roles::new().assign(&dispatcher.context(&peri), 13, Role::Blacklisted)
+            .unwrap();
+        let run = |qq, content| {
+            match dispatcher.dispatch(&peri, &make_msg_in(qq, content)) {
+                Outcome::Reply(Msg::Text(x)) => Ok(x),
+                Outcome::Fail(Msg::Text(x)) => Err(x),
+                x => panic!("unexpected outcome {:?}", x),
+            }
+        };
+
+        assert!(dispatcher.on_event(&peri, &friend(10, "f1")));
+        assert!(dispatcher.on_event(&peri, &friend(13, "f2")));
+        assert!(dispatcher.on_event(&peri, &grp_req(11, "", "i1", true)));
+        assert_eq!(run(1, "#allow group 20"),
+                   Ok("Group 20 is on the allowlist.".to_owned()));
+        assert!(dispatcher.on_event(&peri, &grp_req(11, "", "i2", true)));
+        assert!(dispatcher.on_event(&peri,
+                                    &grp_req(12, "penguins!", "j1", false)));
+        assert_eq!(peri.take_actions(), vec![
+            Action::AnswerFriendRequest {
+                flag: "f1".to_owned(),
+                approve: true,
+                remark: String::new(),
+            },
+            Action::AnswerFriendRequest {
+                flag: "f2".to_owned(),
+                approve: false,
+                remark: String::new(),
+            },
+            answered("i1", true, false),
+            answered("i2", true, true),
+            answered("j1", false, true),
+        ]);
+        peri.take_sent();
+
+        // Join requests without keywords are forwarded to the owner, and
+        // admins assigned in chat.
+        Roles::new().assign(&dispatcher.context(&peri), 14, Role::BotAdmin)
+            .unwrap();
+        assert!(dispatcher.on_event(&peri, &grp_req(12, "hi", "j2", false)));
+        assert!(peri.take_actions().is_empty());
+        match peri.take_sent().as_slice() {
+            [Sent::Private(1, raw), Sent::Private(14, raw2)] => {
+                assert!(raw.starts_with("Request 1: 12 wants to join group \
+                                         20: hi."));
+                assert_eq!(raw, raw2);
+            },
+            x => panic!("unexpected messages {:?}", x),
+        }
+        assert_eq!(run(1, "#requests"),
+                   Ok("Requests:\n1: 12 wants to join group 20: hi"
+                      .to_owned()));
+        assert!(run(2, "#approve 1").is_err());
+        assert_eq!(run(1, "#approve 1"),
+                   Ok("Approved: 12 wants to join group 20: hi.".to_owned()));
+        assert_eq!(peri.take_actions(), vec![answered("j2", false, true)]);
+        assert_eq!(run(1, "#reject 1"),
+                   Err("There is no request 1.".to_owned()));
+        assert_eq!(run(1, "#requests"),
+                   Ok("No requests are waiting.".to_owned()));
+        // Listed along with other admin commands.
+        assert!(run(1, "#help").unwrap().contains("\n#approve <id:int>"));
+    }
+}
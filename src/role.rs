@@ -0,0 +1,245 @@
+//! Roles of users, deciding what they are allowed to do with the bot.
+//!
+//! Bot owners and initial bot admins are listed in config. Group owners and
+//! group admins are told by the peripheral. Bot admins and blacklisted users
+//! can also be assigned in chat, and those assignments are kept in storage.
+use std::fmt;
+use failure::{err_msg, Error};
+use backend::Context;
+use msg::{Msg, MsgIn};
+use peripheral::MemberRole;
+use storage::{Scope, Store};
+
+/// Storage owner of role assignments.
+pub const IDENTITY: &'static str = "liongbot.roles";
+const KEY: &'static str = "role";
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum Role {
+    /// Ignored by all backends.
+    Blacklisted,
+    Member,
+    GroupAdmin,
+    GroupOwner,
+    BotAdmin,
+    BotOwner,
+}
+impl Role {
+    /// Parse a role name like `bot_admin`.
+    pub fn from_name(name: &str) -> Result<Role, Error> {
+        let rv = match name {
+            "blacklisted" => Role::Blacklisted,
+            "member" => Role::Member,
+            "group_admin" => Role::GroupAdmin,
+            "group_owner" => Role::GroupOwner,
+            "bot_admin" => Role::BotAdmin,
+            "bot_owner" => Role::BotOwner,
+            _ => return Err(err_msg(format!("unknown role `{}`", name))),
+        };
+        Ok(rv)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Blacklisted => "blacklisted",
+            Role::Member => "member",
+            Role::GroupAdmin => "group_admin",
+            Role::GroupOwner => "group_owner",
+            Role::BotAdmin => "bot_admin",
+            Role::BotOwner => "bot_owner",
+        }
+    }
+    /// Whether the role can be assigned in chat.
+    pub fn is_assignable(&self) -> bool {
+        matches!(self, Role::Blacklisted | Role::Member | Role::BotAdmin)
+    }
+}
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Blacklisted => "blacklisted user",
+            Role::Member => "member",
+            Role::GroupAdmin => "group admin",
+            Role::GroupOwner => "group owner",
+            Role::BotAdmin => "bot admin",
+            Role::BotOwner => "bot owner",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Polite refusal to someone without the `required` role.
+pub fn refusal(required: Role) -> Msg {
+    ::msg::text(&format!("Sorry, only a {} or above can do this.", required))
+}
+
+/// Roles given in config.
+#[derive(Clone, Debug, Default)]
+pub struct Roles {
+    owners: Vec<i64>,
+    admins: Vec<i64>,
+}
+impl Roles {
+    pub fn new() -> Roles {
+        Roles::default()
+    }
+    pub fn with_owner(mut self, qq: i64) -> Roles {
+        self.owners.push(qq);
+        self
+    }
+    pub fn with_admin(mut self, qq: i64) -> Roles {
+        self.admins.push(qq);
+        self
+    }
+
+    fn store<'a>(ctx: &Context<'a>) -> Store<'a> {
+        ctx.store().with_owner(IDENTITY)
+    }
+    /// Role of the user in the whole bot, not considering groups. Config
+    /// overrides assignments in storage, so owners can't be locked out.
+    pub fn bot_role(&self, ctx: &Context, qq: i64) -> Result<Role, Error> {
+        if self.owners.contains(&qq) {
+            return Ok(Role::BotOwner)
+        }
+        if self.admins.contains(&qq) {
+            return Ok(Role::BotAdmin)
+        }
+        let assigned = Roles::store(ctx).get_json(Scope::User(qq), KEY)?;
+        Ok(assigned.unwrap_or(Role::Member))
+    }
+    /// Bot owners and bot admins, whether given in config or assigned in
+    /// chat.
+    pub fn bot_admins(&self, ctx: &Context) -> Result<Vec<i64>, Error> {
+        let mut rv = Vec::new();
+        for &qq in self.owners.iter().chain(self.admins.iter()) {
+            if !rv.contains(&qq) {
+                rv.push(qq);
+            }
+        }
+        for scope in Roles::store(ctx).scopes(KEY)? {
+            let qq = match scope {
+                Scope::User(qq) if !rv.contains(&qq) => qq,
+                _ => continue,
+            };
+            if self.bot_role(ctx, qq)? >= Role::BotAdmin {
+                rv.push(qq);
+            }
+        }
+        Ok(rv)
+    }
+    /// Assign a role to a user in storage.
+    pub fn assign(&self, ctx: &Context, qq: i64, role: Role)
+            -> Result<(), Error> {
+        if !role.is_assignable() {
+            return Err(err_msg(format!("{} cannot be assigned", role)))
+        }
+        let store = Roles::store(ctx);
+        if role == Role::Member {
+            store.remove(Scope::User(qq), KEY)
+        } else {
+            store.set_json(Scope::User(qq), KEY, &role)
+        }
+    }
+    /// Role of the sender of a message. Group roles are only looked up when
+    /// the bot role isn't enough, so that the peripheral isn't asked for
+    /// nothing.
+    fn role_at_least(&self, ctx: &Context, msg_in: &MsgIn, required: Role)
+            -> Result<Role, Error> {
+        // Anonymous members all share a number, whose role is nobody's.
+        if msg_in.anon().is_some() {
+            return Ok(Role::Member)
+        }
+        let bot_role = self.bot_role(ctx, msg_in.qq())?;
+        if bot_role == Role::Blacklisted || bot_role >= required {
+            return Ok(bot_role)
+        }
+        let grp_role = match msg_in {
+            MsgIn::Group { grp, qq, .. } if required > Role::Member => {
+                match ctx.member_info(*grp, *qq).map(|info| info.role) {
+                    Ok(MemberRole::Owner) => Role::GroupOwner,
+                    Ok(MemberRole::Admin) => Role::GroupAdmin,
+                    // Unknown members are treated as regular members.
+                    _ => Role::Member,
+                }
+            },
+            _ => Role::Member,
+        };
+        Ok(::std::cmp::max(bot_role, grp_role))
+    }
+    /// Role of the sender of a message.
+    pub fn role_of(&self, ctx: &Context, msg_in: &MsgIn)
+            -> Result<Role, Error> {
+        self.role_at_least(ctx, msg_in, Role::BotOwner)
+    }
+    /// Check whether the sender of a message has the `required` role.
+    pub fn check(&self, ctx: &Context, msg_in: &MsgIn, required: Role)
+            -> Result<bool, Error> {
+        Ok(self.role_at_least(ctx, msg_in, required)? >= required)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::Anonymous;
+    use peripheral::MemberInfo;
+    use peripheral::memory::MemoryPeripheral;
+    use peripheral::coolq::CoolQComposer;
+    use storage::MemoryStorage;
+
+    #[test]
+    fn test_role_of() {
+        let peri = MemoryPeripheral::new();
+        peri.add_member(MemberInfo {
+            grp: 10,
+            qq: 3,
+            role: MemberRole::Admin,
+            ..Default::default()
+        });
+        let composer = CoolQComposer::new("C:/");
+        let storage = MemoryStorage::new();
+        let ctx = Context::new(&peri, &composer).with_storage(&storage);
+        let roles = Roles::new().with_owner(1).with_admin(2);
+        let grp_msg_in = |qq| MsgIn::Group {
+            grp: 10,
+            qq: qq,
+            alias: String::new(),
+            grp_alias: String::new(),
+            content: ::msg::text(""),
+            anon: None,
+        };
+        let role_of = |qq| roles.role_of(&ctx, &grp_msg_in(qq)).unwrap();
+        assert_eq!(role_of(1), Role::BotOwner);
+        assert_eq!(role_of(2), Role::BotAdmin);
+        assert_eq!(role_of(3), Role::GroupAdmin);
+        assert_eq!(role_of(4), Role::Member);
+
+        roles.assign(&ctx, 3, Role::Blacklisted).unwrap();
+        roles.assign(&ctx, 4, Role::BotAdmin).unwrap();
+        assert_eq!(role_of(3), Role::Blacklisted);
+        assert_eq!(role_of(4), Role::BotAdmin);
+        assert_eq!(roles.bot_admins(&ctx).unwrap(), vec![1, 2, 4]);
+        roles.assign(&ctx, 3, Role::Member).unwrap();
+        assert_eq!(role_of(3), Role::GroupAdmin);
+        // Config wins.
+        roles.assign(&ctx, 1, Role::Blacklisted).unwrap();
+        assert_eq!(role_of(1), Role::BotOwner);
+        assert!(roles.assign(&ctx, 4, Role::BotOwner).is_err());
+
+        assert!(roles.check(&ctx, &grp_msg_in(3), Role::GroupAdmin).unwrap());
+
+        // Anonymous members are members, whatever the number they share is.
+        roles.assign(&ctx, 80000000, Role::Blacklisted).unwrap();
+        let mut anon_msg_in = grp_msg_in(80000000);
+        if let MsgIn::Group { ref mut anon, .. } = anon_msg_in {
+            *anon = Some(Anonymous {
+                id: 5,
+                name: String::new(),
+                token: String::new(),
+            });
+        }
+        assert_eq!(roles.role_of(&ctx, &anon_msg_in).unwrap(), Role::Member);
+        assert!(!roles.check(&ctx, &grp_msg_in(3), Role::BotAdmin).unwrap());
+    }
+}
@@ -0,0 +1,38 @@
+//! Database schema, matching the migrations in `migrations/`.
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use failure::{err_msg, Error};
+
+embed_migrations!();
+
+/// Connect to the SQLite database at `path`, creating it if it doesn't exist,
+/// and run pending migrations.
+pub fn establish(path: &str) -> Result<SqliteConnection, Error> {
+    let conn = SqliteConnection::establish(path)?;
+    embedded_migrations::run(&conn)
+        .map_err(|err| err_msg(format!("migration failed: {}", err)))?;
+    Ok(conn)
+}
+
+table! {
+    history (id) {
+        id -> BigInt,
+        outgoing -> Bool,
+        qq -> Nullable<BigInt>,
+        grp -> Nullable<BigInt>,
+        alias -> Text,
+        timestamp -> BigInt,
+        content -> Text,
+        discuss -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    storage (owner, scope, scope_id, name) {
+        owner -> Text,
+        scope -> Text,
+        scope_id -> BigInt,
+        name -> Text,
+        value -> Text,
+    }
+}
@@ -0,0 +1,323 @@
+//! Typed views of the common non-text message segments. Composers only see
+//! `Msg::Ext`, while backends can build and match segments with checked
+//! fields.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use failure::{err_msg, Error};
+use serde_json::{self, Value};
+use msg::{ExtBuilder, Msg};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    /// Mention a user.
+    At { qq: i64 },
+    /// Mention everyone in a group.
+    AtAll,
+    Image { file: PathBuf },
+    /// Voice record. Magic records have the voice changed.
+    Record { file: PathBuf, magic: bool },
+    /// QQ built-in face.
+    Face { id: u32 },
+    /// Unicode emoji, by code point.
+    Emoji { id: u32 },
+    /// Link sharing.
+    Share {
+        url: String,
+        title: String,
+        content: Option<String>,
+        image: Option<String>,
+    },
+    Location {
+        lat: f64,
+        lon: f64,
+        title: String,
+        content: String,
+    },
+    /// Music from a platform, like `qq`, `163` or `xm`.
+    Music { platform: String, id: String },
+    /// Music of custom source.
+    CustomMusic {
+        url: String,
+        audio: String,
+        title: String,
+        content: Option<String>,
+        image: Option<String>,
+    },
+    /// Reply to a message.
+    Reply { id: i64 },
+    /// Rich message in JSON.
+    Rich { title: Option<String>, content: Value },
+}
+
+struct Params<'a> {
+    name: &'a str,
+    params: &'a BTreeMap<String, String>,
+}
+impl<'a> Params<'a> {
+    fn opt(&self, key: &str) -> Option<String> {
+        self.params.get(key).cloned()
+    }
+    fn get(&self, key: &str) -> Result<String, Error> {
+        self.opt(key).ok_or_else(|| {
+            err_msg(format!("`{}` is missing in `{}`", key, self.name))
+        })
+    }
+    fn parse<T: FromStr>(&self, key: &str) -> Result<T, Error> {
+        self.get(key)?.parse::<T>().map_err(|_| {
+            err_msg(format!("`{}` is invalid in `{}`", key, self.name))
+        })
+    }
+}
+
+impl Segment {
+    /// Name of the segment in `Msg::Ext`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::At { .. } | Segment::AtAll => "at",
+            Segment::Image { .. } => "image",
+            Segment::Record { .. } => "record",
+            Segment::Face { .. } => "face",
+            Segment::Emoji { .. } => "emoji",
+            Segment::Share { .. } => "share",
+            Segment::Location { .. } => "location",
+            Segment::Music { .. } | Segment::CustomMusic { .. } => "music",
+            Segment::Reply { .. } => "reply",
+            Segment::Rich { .. } => "rich",
+        }
+    }
+    /// Check whether field values are acceptable.
+    pub fn validate(&self) -> Result<(), Error> {
+        let ok = match self {
+            Segment::At { qq } => *qq > 0,
+            Segment::Image { file } |
+            Segment::Record { file, .. } => !file.as_os_str().is_empty(),
+            Segment::Emoji { id } => ::std::char::from_u32(*id).is_some(),
+            Segment::Share { url, title, .. } => {
+                !url.is_empty() && !title.is_empty()
+            },
+            Segment::Location { lat, lon, .. } => {
+                lat.abs() <= 90.0 && lon.abs() <= 180.0
+            },
+            Segment::Music { platform, id } => {
+                !platform.is_empty() && platform != "custom" && !id.is_empty()
+            },
+            Segment::CustomMusic { url, audio, title, .. } => {
+                !url.is_empty() && !audio.is_empty() && !title.is_empty()
+            },
+            Segment::Rich { content, .. } => content.is_object(),
+            Segment::AtAll | Segment::Face { .. } | Segment::Reply { .. } => {
+                true
+            },
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(err_msg(format!("invalid `{}` segment: {:?}",
+                                self.name(), self)))
+        }
+    }
+    /// Parse a `Msg::Ext` of a known segment, and validate it.
+    pub fn from_msg(msg: &Msg) -> Result<Segment, Error> {
+        let (name, params) = match msg {
+            Msg::Ext { ref name, ref params } => (name.as_str(), params),
+            _ => return Err(err_msg("only extensions can be segments")),
+        };
+        let p = Params { name: name, params: params };
+        let rv = match name {
+            "at" if p.opt("qq").as_deref() == Some("all") => {
+                Segment::AtAll
+            },
+            "at" => Segment::At { qq: p.parse("qq")? },
+            "image" => Segment::Image { file: PathBuf::from(p.get("file")?) },
+            "record" => Segment::Record {
+                file: PathBuf::from(p.get("file")?),
+                magic: p.opt("magic").as_deref() == Some("true"),
+            },
+            "face" => Segment::Face { id: p.parse("id")? },
+            "emoji" => Segment::Emoji { id: p.parse("id")? },
+            "share" => Segment::Share {
+                url: p.get("url")?,
+                title: p.get("title")?,
+                content: p.opt("content"),
+                image: p.opt("image"),
+            },
+            "location" => Segment::Location {
+                lat: p.parse("lat")?,
+                lon: p.parse("lon")?,
+                title: p.opt("title").unwrap_or_default(),
+                content: p.opt("content").unwrap_or_default(),
+            },
+            "music" if p.opt("type").as_deref() == Some("custom") => {
+                Segment::CustomMusic {
+                    url: p.get("url")?,
+                    audio: p.get("audio")?,
+                    title: p.get("title")?,
+                    content: p.opt("content"),
+                    image: p.opt("image"),
+                }
+            },
+            "music" => Segment::Music {
+                platform: p.get("type")?,
+                id: p.get("id")?,
+            },
+            "reply" => Segment::Reply { id: p.parse("id")? },
+            "rich" => Segment::Rich {
+                title: p.opt("title"),
+                content: serde_json::from_str(&p.get("content")?)?,
+            },
+            _ => return Err(err_msg(format!("unknown segment `{}`", name))),
+        };
+        rv.validate()?;
+        Ok(rv)
+    }
+    /// Convert into a `Msg::Ext` after validation.
+    pub fn to_msg(&self) -> Result<Msg, Error> {
+        self.validate()?;
+        Ok(self.to_msg_unchecked())
+    }
+    fn to_msg_unchecked(&self) -> Msg {
+        let mut b = ExtBuilder::new(self.name());
+        match self {
+            Segment::At { qq } => b.add_param("qq", qq),
+            Segment::AtAll => b.add_param("qq", "all"),
+            Segment::Image { file } => {
+                b.add_param("file", &file.to_string_lossy());
+            },
+            Segment::Record { file, magic } => {
+                b.add_param("file", &file.to_string_lossy());
+                if *magic {
+                    b.add_param("magic", "true");
+                }
+            },
+            Segment::Face { id } | Segment::Emoji { id } => {
+                b.add_param("id", id);
+            },
+            Segment::Share { url, title, content, image } => {
+                b.add_param("url", url);
+                b.add_param("title", title);
+                if let Some(content) = content {
+                    b.add_param("content", content);
+                }
+                if let Some(image) = image {
+                    b.add_param("image", image);
+                }
+            },
+            Segment::Location { lat, lon, title, content } => {
+                b.add_param("lat", lat);
+                b.add_param("lon", lon);
+                b.add_param("title", title);
+                b.add_param("content", content);
+            },
+            Segment::Music { platform, id } => {
+                b.add_param("type", platform);
+                b.add_param("id", id);
+            },
+            Segment::CustomMusic { url, audio, title, content, image } => {
+                b.add_param("type", "custom");
+                b.add_param("url", url);
+                b.add_param("audio", audio);
+                b.add_param("title", title);
+                if let Some(content) = content {
+                    b.add_param("content", content);
+                }
+                if let Some(image) = image {
+                    b.add_param("image", image);
+                }
+            },
+            Segment::Reply { id } => b.add_param("id", id),
+            Segment::Rich { title, content } => {
+                if let Some(title) = title {
+                    b.add_param("title", title);
+                }
+                b.add_param("content", content);
+            },
+        }
+        b.build()
+    }
+}
+impl From<Segment> for Msg {
+    /// Convert into a `Msg::Ext` without validation.
+    fn from(seg: Segment) -> Msg {
+        seg.to_msg_unchecked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg::*;
+
+    fn round_trip(seg: Segment) {
+        let msg = seg.to_msg().unwrap();
+        assert_eq!(Segment::from_msg(&msg).unwrap(), seg);
+    }
+    #[test]
+    fn test_round_trip() {
+        round_trip(Segment::At { qq: 123 });
+        round_trip(Segment::AtAll);
+        round_trip(Segment::Image { file: PathBuf::from("1.jpg") });
+        round_trip(Segment::Record {
+            file: PathBuf::from("1.silk"),
+            magic: true,
+        });
+        round_trip(Segment::Face { id: 14 });
+        round_trip(Segment::Emoji { id: 0x1f427 });
+        round_trip(Segment::Share {
+            url: "https://penguinliong.moe".to_owned(),
+            title: "Liong".to_owned(),
+            content: None,
+            image: Some("https://penguinliong.moe/1.jpg".to_owned()),
+        });
+        round_trip(Segment::Location {
+            lat: 22.5,
+            lon: 113.9,
+            title: "Somewhere".to_owned(),
+            content: String::new(),
+        });
+        round_trip(Segment::Music {
+            platform: "163".to_owned(),
+            id: "28949129".to_owned(),
+        });
+        round_trip(Segment::CustomMusic {
+            url: "https://a.b/".to_owned(),
+            audio: "https://a.b/1.mp3".to_owned(),
+            title: "Song".to_owned(),
+            content: Some("Singer".to_owned()),
+            image: None,
+        });
+        round_trip(Segment::Reply { id: -1 });
+        round_trip(Segment::Rich {
+            title: None,
+            content: serde_json::from_str(r#"{"app":"x"}"#).unwrap(),
+        });
+    }
+    #[test]
+    fn test_shortcut() {
+        assert_eq!(Msg::from(Segment::At { qq: 123 }), at(123));
+        assert_eq!(Segment::from_msg(&image("1.jpg")).unwrap(),
+                   Segment::Image { file: PathBuf::from("1.jpg") });
+        assert_eq!(Segment::from_msg(&record("1.silk")).unwrap(),
+                   Segment::Record {
+                       file: PathBuf::from("1.silk"),
+                       magic: false,
+                   });
+    }
+    #[test]
+    fn test_invalid() {
+        assert!(Segment::from_msg(&text("123")).is_err());
+        assert!(Segment::from_msg(&ExtBuilder::new("x").build()).is_err());
+        let at_nobody = ExtBuilder::new("at").with_param("qq", "x").build();
+        assert!(Segment::from_msg(&at_nobody).is_err());
+        let far_away = ExtBuilder::new("location")
+            .with_param("lat", "91")
+            .with_param("lon", "0")
+            .build();
+        assert!(Segment::from_msg(&far_away).is_err());
+        let bad_rich = ExtBuilder::new("rich")
+            .with_param("content", "[]")
+            .build();
+        assert!(Segment::from_msg(&bad_rich).is_err());
+        assert!(Segment::Emoji { id: 0xd800 }.to_msg().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+//! Persistent key-value state for backends. Entries are owned by backends,
+//! by their identities, and are scoped to a user, a group, or the whole bot.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use failure::{err_msg, Error};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use schema::{self, storage};
+
+/// Who a stored entry belongs to.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Scope {
+    Global,
+    User(i64),
+    Group(i64),
+    Discuss(i64),
+    /// An anonymous group member, by anonymous id.
+    Anonymous(i64),
+}
+impl Scope {
+    fn split(&self) -> (&'static str, i64) {
+        match *self {
+            Scope::Global => ("global", 0),
+            Scope::User(qq) => ("user", qq),
+            Scope::Group(grp) => ("group", grp),
+            Scope::Discuss(discuss) => ("discuss", discuss),
+            Scope::Anonymous(id) => ("anonymous", id),
+        }
+    }
+    fn join(scope: &str, id: i64) -> Result<Scope, Error> {
+        let rv = match scope {
+            "global" => Scope::Global,
+            "user" => Scope::User(id),
+            "group" => Scope::Group(id),
+            "discuss" => Scope::Discuss(id),
+            "anonymous" => Scope::Anonymous(id),
+            _ => return Err(err_msg(format!("unknown scope `{}`", scope))),
+        };
+        Ok(rv)
+    }
+}
+
+pub trait Storage {
+    fn get(&self, owner: &str, scope: Scope, key: &str)
+        -> Result<Option<String>, Error>;
+    fn set(&self, owner: &str, scope: Scope, key: &str, value: &str)
+        -> Result<(), Error>;
+    fn remove(&self, owner: &str, scope: Scope, key: &str)
+        -> Result<(), Error>;
+    /// Scopes having an entry of `key`.
+    fn scopes(&self, owner: &str, key: &str) -> Result<Vec<Scope>, Error>;
+}
+
+/// Storage kept in memory, lost when the bot stops.
+pub struct MemoryStorage {
+    entries: RefCell<BTreeMap<(String, Scope, String), String>>,
+}
+impl Default for MemoryStorage {
+    fn default() -> MemoryStorage {
+        MemoryStorage::new()
+    }
+}
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage { entries: RefCell::new(BTreeMap::new()) }
+    }
+}
+impl Storage for MemoryStorage {
+    fn get(&self, owner: &str, scope: Scope, key: &str)
+            -> Result<Option<String>, Error> {
+        let k = (owner.to_owned(), scope, key.to_owned());
+        Ok(self.entries.borrow().get(&k).cloned())
+    }
+    fn set(&self, owner: &str, scope: Scope, key: &str, value: &str)
+            -> Result<(), Error> {
+        let k = (owner.to_owned(), scope, key.to_owned());
+        self.entries.borrow_mut().insert(k, value.to_owned());
+        Ok(())
+    }
+    fn remove(&self, owner: &str, scope: Scope, key: &str)
+            -> Result<(), Error> {
+        let k = (owner.to_owned(), scope, key.to_owned());
+        self.entries.borrow_mut().remove(&k);
+        Ok(())
+    }
+    fn scopes(&self, owner: &str, key: &str) -> Result<Vec<Scope>, Error> {
+        let rv = self.entries.borrow().keys()
+            .filter(|(o, _, k)| o == owner && k == key)
+            .map(|(_, scope, _)| *scope)
+            .collect();
+        Ok(rv)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="storage"]
+struct NewEntry<'a> {
+    owner: &'a str,
+    scope: &'a str,
+    scope_id: i64,
+    name: &'a str,
+    value: &'a str,
+}
+
+/// Storage in a SQLite database. It can share the database file with
+/// `History`.
+pub struct SqliteStorage {
+    conn: SqliteConnection,
+}
+impl SqliteStorage {
+    /// Open the database at `path`, creating it if it doesn't exist. Pending
+    /// migrations are run.
+    pub fn open(path: &str) -> Result<SqliteStorage, Error> {
+        Ok(SqliteStorage { conn: schema::establish(path)? })
+    }
+}
+impl Storage for SqliteStorage {
+    fn get(&self, owner: &str, scope: Scope, key: &str)
+            -> Result<Option<String>, Error> {
+        let (scope, scope_id) = scope.split();
+        let rv = storage::table
+            .filter(storage::owner.eq(owner))
+            .filter(storage::scope.eq(scope))
+            .filter(storage::scope_id.eq(scope_id))
+            .filter(storage::name.eq(key))
+            .select(storage::value)
+            .first::<String>(&self.conn)
+            .optional()?;
+        Ok(rv)
+    }
+    fn set(&self, owner: &str, scope: Scope, key: &str, value: &str)
+            -> Result<(), Error> {
+        let (scope, scope_id) = scope.split();
+        ::diesel::replace_into(storage::table)
+            .values(&NewEntry {
+                owner: owner,
+                scope: scope,
+                scope_id: scope_id,
+                name: key,
+                value: value,
+            })
+            .execute(&self.conn)?;
+        Ok(())
+    }
+    fn remove(&self, owner: &str, scope: Scope, key: &str)
+            -> Result<(), Error> {
+        let (scope, scope_id) = scope.split();
+        let entry = storage::table
+            .filter(storage::owner.eq(owner))
+            .filter(storage::scope.eq(scope))
+            .filter(storage::scope_id.eq(scope_id))
+            .filter(storage::name.eq(key));
+        ::diesel::delete(entry).execute(&self.conn)?;
+        Ok(())
+    }
+    fn scopes(&self, owner: &str, key: &str) -> Result<Vec<Scope>, Error> {
+        storage::table
+            .filter(storage::owner.eq(owner))
+            .filter(storage::name.eq(key))
+            .select((storage::scope, storage::scope_id))
+            .load::<(String, i64)>(&self.conn)?
+            .into_iter()
+            .map(|(scope, scope_id)| Scope::join(&scope, scope_id))
+            .collect()
+    }
+}
+
+/// Storage seen by a single backend. Backends can't see each other's
+/// entries.
+#[derive(Clone, Copy)]
+pub struct Store<'a> {
+    storage: Option<&'a Storage>,
+    owner: &'a str,
+}
+impl<'a> Store<'a> {
+    pub fn new(storage: Option<&'a Storage>, owner: &'a str) -> Store<'a> {
+        Store {
+            storage: storage,
+            owner: owner,
+        }
+    }
+    fn storage(&self) -> Result<&'a Storage, Error> {
+        self.storage.ok_or_else(|| err_msg("storage is not available"))
+    }
+    pub fn owner(&self) -> &'a str {
+        self.owner
+    }
+    /// The same storage seen by another owner. Backends can't use it, only
+    /// built-in modules keeping entries of their own, like roles, can.
+    pub(crate) fn with_owner(mut self, owner: &'a str) -> Store<'a> {
+        self.owner = owner;
+        self
+    }
+    pub fn get(&self, scope: Scope, key: &str)
+            -> Result<Option<String>, Error> {
+        self.storage()?.get(self.owner, scope, key)
+    }
+    pub fn set(&self, scope: Scope, key: &str, value: &str)
+            -> Result<(), Error> {
+        self.storage()?.set(self.owner, scope, key, value)
+    }
+    pub fn remove(&self, scope: Scope, key: &str) -> Result<(), Error> {
+        self.storage()?.remove(self.owner, scope, key)
+    }
+    /// Scopes having an entry of `key`.
+    pub fn scopes(&self, key: &str) -> Result<Vec<Scope>, Error> {
+        self.storage()?.scopes(self.owner, key)
+    }
+    /// Get an entry stored by `set_json`.
+    pub fn get_json<T>(&self, scope: Scope, key: &str)
+            -> Result<Option<T>, Error> where T: DeserializeOwned {
+        match self.get(scope, key)? {
+            Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            None => Ok(None),
+        }
+    }
+    pub fn set_json<T>(&self, scope: Scope, key: &str, value: &T)
+            -> Result<(), Error> where T: Serialize {
+        self.set(scope, key, &serde_json::to_string(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(storage: &Storage) {
+        let a = Store::new(Some(storage), "a");
+        let b = Store::new(Some(storage), "b");
+        a.set(Scope::User(1), "x", "1").unwrap();
+        a.set(Scope::Group(1), "x", "2").unwrap();
+        b.set(Scope::User(1), "x", "3").unwrap();
+        assert_eq!(a.get(Scope::User(1), "x").unwrap(), Some("1".to_owned()));
+        assert_eq!(a.get(Scope::Group(1), "x").unwrap(), Some("2".to_owned()));
+        assert_eq!(b.get(Scope::User(1), "x").unwrap(), Some("3".to_owned()));
+        assert_eq!(a.get(Scope::Global, "x").unwrap(), None);
+
+        a.set(Scope::User(1), "x", "4").unwrap();
+        assert_eq!(a.get(Scope::User(1), "x").unwrap(), Some("4".to_owned()));
+        a.remove(Scope::User(1), "x").unwrap();
+        assert_eq!(a.get(Scope::User(1), "x").unwrap(), None);
+        assert_eq!(b.get(Scope::User(1), "x").unwrap(), Some("3".to_owned()));
+        let mut scopes = a.scopes("x").unwrap();
+        scopes.sort();
+        assert_eq!(scopes, vec![Scope::Group(1)]);
+        a.set(Scope::Anonymous(5), "x", "5").unwrap();
+        let mut scopes = a.scopes("x").unwrap();
+        scopes.sort();
+        assert_eq!(scopes, vec![Scope::Group(1), Scope::Anonymous(5)]);
+
+        a.set_json(Scope::Global, "list", &vec![1, 2]).unwrap();
+        assert_eq!(a.get_json::<Vec<i32>>(Scope::Global, "list").unwrap(),
+                   Some(vec![1, 2]));
+    }
+    #[test]
+    fn test_memory() {
+        check(&MemoryStorage::new());
+        let store = Store::new(None, "a");
+        assert!(store.get(Scope::Global, "x").is_err());
+    }
+    #[test]
+    fn test_sqlite() {
+        check(&SqliteStorage::open(":memory:").unwrap());
+    }
+}
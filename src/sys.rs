@@ -1,105 +1,445 @@
 //! Import symbols from `CQP.dll`
-use std::cell::Cell;
 use std::ffi::{CString, CStr};
 use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
 use encoding_rs::GB18030;
-use base64::decode;
-use bytes::{Buf, IntoBuf, Bytes};
+use failure::{err_msg, Error};
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
 use {Dispatcher, Msg, MsgIn};
+use logger;
+use unwind;
+use peripheral::{AdminError, Event, Lifecycle, MemberInfo, Peripheral,
+                 UserInfo};
+use msg::Anonymous;
+use peripheral::coolq::{CoolQComposer, parse_anonymous, parse_file_info,
+                        parse_member_info, parse_user_info};
 
 mod consts {
     pub const APP_INFO: &'static str = "9,moe.penguinliong.liongbot\0";
 
     pub const EVENT_IGNORE: i32 = 0;
     pub const EVENT_BLOCK: i32 = 1;
+
+    pub const LOG_DEBUG: i32 = 0;
+    pub const LOG_INFO: i32 = 10;
+    pub const LOG_WARNING: i32 = 20;
+    pub const LOG_ERROR: i32 = 30;
+
+    pub const REQUEST_ALLOW: i32 = 1;
+    pub const REQUEST_DENY: i32 = 2;
+
+    pub const REQUEST_GROUP_ADD: i32 = 1;
+    pub const REQUEST_GROUP_INVITE: i32 = 2;
 }
 
 static mut DISPATCHER: Option<Dispatcher> = None;
 static mut AUTH: i32 = 0;
 
-pub fn send_priv(qq: i64, msg: &str) {
+fn check(api: &str, code: i32) -> Result<(), Error> {
+    if code < 0 {
+        Err(err_msg(format!("{} failed with code {}", api, code)))
+    } else {
+        Ok(())
+    }
+}
+/// Check the code returned by an administrative API.
+fn check_admin(code: i32) -> Result<(), AdminError> {
+    if code < 0 {
+        Err(AdminError::Failed(code as i64))
+    } else {
+        Ok(())
+    }
+}
+fn response_of(approve: bool) -> i32 {
+    if approve { consts::REQUEST_ALLOW } else { consts::REQUEST_DENY }
+}
+fn encode(text: &str) -> Result<CString, Error> {
+    let (buf, _, _) = GB18030.encode(text);
+    Ok(CString::new(buf)?)
+}
+/// Encode an argument of an administrative API. Text CoolQ can't take is
+/// bad input.
+fn encode_arg(text: &str) -> Result<CString, AdminError> {
+    encode(text).map_err(|err| AdminError::Invalid(err.to_string()))
+}
+/// Copy out a string returned by CoolQ.
+fn check_str(api: &str, ptr: *const c_char) -> Result<Vec<u8>, Error> {
+    if ptr.is_null() {
+        return Err(err_msg(format!("{} returned null", api)))
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }.to_bytes().to_owned())
+}
+pub fn add_log(priority: i32, category: &str, content: &str) {
     #[no_mangle]
     #[link(name="CQP")]
     extern {
-        #[link_name="CQ_sendPrivateMsg"]
-        fn native(auth: i32, qq: i64, msg: *const c_char) -> i32;
+        #[link_name="CQ_addLog"]
+        fn native(auth: i32, priority: i32, category: *const c_char,
+                  content: *const c_char) -> i32;
+    }
+    if let (Ok(category), Ok(content)) = (encode(category), encode(content)) {
+        let _ = unsafe {
+            native(AUTH, priority, category.as_ptr(), content.as_ptr())
+        };
+    }
+}
+
+/// Logger forwarding records to CoolQ's log, categorized by their targets.
+struct CoolQLogger;
+impl Log for CoolQLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+        let priority = match record.level() {
+            Level::Error => consts::LOG_ERROR,
+            Level::Warn => consts::LOG_WARNING,
+            Level::Info => consts::LOG_INFO,
+            Level::Debug | Level::Trace => consts::LOG_DEBUG,
+        };
+        add_log(priority, record.target(), &logger::content(record));
     }
-    let (buf, _, _) = GB18030.encode(msg);
-    // FIXME: (penguinliong) Check result.
-    let _ = unsafe { native(AUTH, qq, CString::new(buf).unwrap().as_ptr()) };
+    fn flush(&self) {}
 }
-pub fn send_grp(grp: i64, qq: i64, msg: &str) {
+static COOLQ_LOGGER: CoolQLogger = CoolQLogger;
+/// Tell CoolQ the app has run into an unrecoverable state.
+fn set_fatal(info: &str) {
     #[no_mangle]
     #[link(name="CQP")]
     extern {
-        #[link_name="CQ_sendGroupMsg"]
-        fn native(auth: i32, grp: i64, msg: *const c_char) -> i32;
+        #[link_name="CQ_setFatal"]
+        fn native(auth: i32, info: *const c_char) -> i32;
+    }
+    if let Ok(info) = encode(info) {
+        let _ = unsafe { native(AUTH, info.as_ptr()) };
     }
-    // FIXME: (penguinliong) Check result.
-    let (buf, _, _) = GB18030.encode(msg);
-    let _ = unsafe { native(AUTH, grp, CString::new(buf).unwrap().as_ptr()); };
 }
-pub fn skip_string(b: &mut Buf) {
-    loop {
-        match b.get_u8() {
-            0 => break,
-            _ => continue,
+
+/// CoolQ as a peripheral, calling into `CQP.dll`.
+pub struct CoolQ;
+impl Peripheral for CoolQ {
+    fn name(&self) -> &'static str {
+        "peripheral.coolq"
+    }
+    fn send_priv(&self, qq: i64, raw: &str) -> Result<(), Error> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_sendPrivateMsg"]
+            fn native(auth: i32, qq: i64, msg: *const c_char) -> i32;
         }
+        let buf = encode(raw)?;
+        check("CQ_sendPrivateMsg", unsafe { native(AUTH, qq, buf.as_ptr()) })
     }
-}
-pub fn get_string(b: &mut Buf) -> String {
-    let mut buf = Vec::new();
-    loop {
-        match b.get_u8() {
-            0 => break,
-            c => buf.push(c),
+    fn send_grp(&self, grp: i64, raw: &str) -> Result<(), Error> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_sendGroupMsg"]
+            fn native(auth: i32, grp: i64, msg: *const c_char) -> i32;
+        }
+        let buf = encode(raw)?;
+        check("CQ_sendGroupMsg", unsafe { native(AUTH, grp, buf.as_ptr()) })
+    }
+    fn send_discuss(&self, discuss: i64, raw: &str) -> Result<(), Error> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_sendDiscussMsg"]
+            fn native(auth: i32, discuss: i64, msg: *const c_char) -> i32;
+        }
+        let buf = encode(raw)?;
+        check("CQ_sendDiscussMsg",
+              unsafe { native(AUTH, discuss, buf.as_ptr()) })
+    }
+    fn user_info(&self, qq: i64) -> Result<UserInfo, Error> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_getStrangerInfo"]
+            fn native(auth: i32, qq: i64, no_cache: i32) -> *const c_char;
+        }
+        let b64 = check_str("CQ_getStrangerInfo",
+                            unsafe { native(AUTH, qq, 0) })?;
+        parse_user_info(&b64)
+    }
+    fn member_info(&self, grp: i64, qq: i64) -> Result<MemberInfo, Error> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_getGroupMemberInfoV2"]
+            fn native (auth: i32, grp: i64, qq: i64, no_cache: i32)
+                -> *const c_char;
         }
+        let b64 = check_str("CQ_getGroupMemberInfoV2",
+                            unsafe { native(AUTH, grp, qq, 0) })?;
+        parse_member_info(&b64)
+    }
+    fn ban(&self, grp: i64, qq: i64, duration: i64)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupBan"]
+            fn native(auth: i32, grp: i64, qq: i64, duration: i64) -> i32;
+        }
+        check_admin(unsafe { native(AUTH, grp, qq, duration) })
+    }
+    fn ban_anonymous(&self, grp: i64, anon: &Anonymous, duration: i64)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupAnonymousBan"]
+            fn native(auth: i32, grp: i64, anon: *const c_char,
+                      duration: i64) -> i32;
+        }
+        let token = encode_arg(&anon.token)?;
+        check_admin(unsafe { native(AUTH, grp, token.as_ptr(), duration) })
+    }
+    fn kick(&self, grp: i64, qq: i64, reject_rejoin: bool)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupKick"]
+            fn native(auth: i32, grp: i64, qq: i64, reject_rejoin: i32)
+                -> i32;
+        }
+        check_admin(unsafe { native(AUTH, grp, qq, reject_rejoin as i32) })
+    }
+    fn set_card(&self, grp: i64, qq: i64, card: &str)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupCard"]
+            fn native(auth: i32, grp: i64, qq: i64, card: *const c_char)
+                -> i32;
+        }
+        let card = encode_arg(card)?;
+        check_admin(unsafe { native(AUTH, grp, qq, card.as_ptr()) })
+    }
+    fn set_admin(&self, grp: i64, qq: i64, admin: bool)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupAdmin"]
+            fn native(auth: i32, grp: i64, qq: i64, admin: i32) -> i32;
+        }
+        check_admin(unsafe { native(AUTH, grp, qq, admin as i32) })
+    }
+    fn set_whole_ban(&self, grp: i64, ban: bool) -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupWholeBan"]
+            fn native(auth: i32, grp: i64, ban: i32) -> i32;
+        }
+        check_admin(unsafe { native(AUTH, grp, ban as i32) })
+    }
+    fn set_special_title(&self, grp: i64, qq: i64, title: &str,
+                         duration: i64) -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupSpecialTitle"]
+            fn native(auth: i32, grp: i64, qq: i64, title: *const c_char,
+                      duration: i64) -> i32;
+        }
+        let title = encode_arg(title)?;
+        check_admin(unsafe {
+            native(AUTH, grp, qq, title.as_ptr(), duration)
+        })
+    }
+    fn leave_group(&self, grp: i64, dispose: bool)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupLeave"]
+            fn native(auth: i32, grp: i64, dispose: i32) -> i32;
+        }
+        check_admin(unsafe { native(AUTH, grp, dispose as i32) })
+    }
+    fn leave_discuss(&self, discuss: i64) -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setDiscussLeave"]
+            fn native(auth: i32, discuss: i64) -> i32;
+        }
+        check_admin(unsafe { native(AUTH, discuss) })
+    }
+    fn answer_friend_request(&self, flag: &str, approve: bool, remark: &str)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setFriendAddRequest"]
+            fn native(auth: i32, flag: *const c_char, response: i32,
+                      remark: *const c_char) -> i32;
+        }
+        let flag = encode_arg(flag)?;
+        let remark = encode_arg(remark)?;
+        let response = response_of(approve);
+        check_admin(unsafe {
+            native(AUTH, flag.as_ptr(), response, remark.as_ptr())
+        })
+    }
+    fn answer_group_request(&self, flag: &str, invited: bool,
+                            approve: bool, reason: &str)
+            -> Result<(), AdminError> {
+        #[no_mangle]
+        #[link(name="CQP")]
+        extern {
+            #[link_name="CQ_setGroupAddRequestV2"]
+            fn native(auth: i32, flag: *const c_char, request_type: i32,
+                      response: i32, reason: *const c_char) -> i32;
+        }
+        let flag = encode_arg(flag)?;
+        let reason = encode_arg(reason)?;
+        let request_type = if invited {
+            consts::REQUEST_GROUP_INVITE
+        } else {
+            consts::REQUEST_GROUP_ADD
+        };
+        let response = response_of(approve);
+        check_admin(unsafe {
+            native(AUTH, flag.as_ptr(), request_type, response,
+                   reason.as_ptr())
+        })
     }
-    // FIXME: (penguinliong) Check result.
-    use encoding_rs::GB18030;
-    let (rv, _) = GB18030.decode_without_bom_handling(&buf);
-    String::from(rv)
 }
-pub fn make_priv_msg_in(qq: i64, content: Msg) -> MsgIn {
+
+fn decode(raw: *const c_char) -> String {
+    let raw = unsafe { CStr::from_ptr(raw) };
+    let (decoded, _) = GB18030.decode_without_bom_handling(raw.to_bytes());
+    decoded.into_owned()
+}
+/// Directory CoolQ assigned to this app for its own files.
+fn app_dir() -> Result<PathBuf, Error> {
     #[no_mangle]
     #[link(name="CQP")]
     extern {
-        #[link_name="CQ_getStrangerInfo"]
-        fn native(auth: i32, qq: i64, no_cache: i32) -> *const c_char;
+        #[link_name="CQ_getAppDirectory"]
+        fn native(auth: i32) -> *const c_char;
     }
-    let b64 = unsafe { CStr::from_ptr(native(AUTH, qq, 0)) };
-    let raw = decode(b64.to_bytes()).unwrap();
-    let mut b = Bytes::from(raw).into_buf();
-
-    b.advance(8);
-    let alias = get_string(&mut b);
+    let ptr = unsafe { native(AUTH) };
+    if ptr.is_null() {
+        return Err(err_msg("CQ_getAppDirectory returned null"))
+    }
+    Ok(PathBuf::from(decode(ptr)))
+}
+/// Directory CoolQ is installed in. Apps are given `data/app/<appid>` in it.
+fn coolq_dir(app_dir: &Path) -> Result<PathBuf, Error> {
+    app_dir.ancestors().nth(3)
+        .map(Path::to_owned)
+        .ok_or_else(|| {
+            err_msg(format!("unexpected app directory {}", app_dir.display()))
+        })
+}
+/// Run the body of the exported function `name`, so that panics don't unwind
+/// into CoolQ. Nobody knows what state the bot is left in after a panic in
+/// lifecycle events, so the app is marked fatal.
+fn guard_fatal<F>(name: &str, f: F) -> i32 where F: FnOnce() -> i32 {
+    unwind::catch(name, f).unwrap_or_else(|msg| {
+        set_fatal(&format!("{} panicked: {}", name, msg));
+        0
+    })
+}
+fn with_dispatcher<F>(f: F) -> i32 where F: FnOnce(&Dispatcher) -> i32 {
+    match unsafe { DISPATCHER.as_ref() } {
+        Some(dispatcher) => f(dispatcher),
+        None => consts::EVENT_IGNORE,
+    }
+}
+fn on_lifecycle(event: Lifecycle) -> i32 {
+    with_dispatcher(|dispatcher| {
+        if let Err(err) = dispatcher.on_lifecycle(&CoolQ, event) {
+            error!("unable to handle {:?}: {}", event, err);
+        }
+        0
+    })
+}
+/// Deliver the event, and tell CoolQ whether the event should be blocked from
+/// other plugins.
+fn on_event(event: Event) -> i32 {
+    with_dispatcher(|dispatcher| {
+        if let Err(err) = dispatcher.poll_reload(&CoolQ) {
+            error!("reload failed: {}", err);
+        }
+        if dispatcher.on_event(&CoolQ, &event) {
+            consts::EVENT_BLOCK
+        } else {
+            consts::EVENT_IGNORE
+        }
+    })
+}
+/// Handle the message, and tell CoolQ whether the message should be blocked
+/// from other plugins.
+fn handle(dispatcher: &Dispatcher, msg_in: &MsgIn) -> i32 {
+    if let Err(err) = dispatcher.poll_reload(&CoolQ) {
+        error!("reload failed: {}", err);
+    }
+    match dispatcher.handle(&CoolQ, msg_in) {
+        Ok(ref outcome) if !outcome.is_consumed() => consts::EVENT_IGNORE,
+        Ok(_) => consts::EVENT_BLOCK,
+        Err(err) => {
+            error!("unable to reply to {}: {}", msg_in.qq(), err);
+            consts::EVENT_BLOCK
+        },
+    }
+}
+fn alias_of(qq: i64) -> String {
+    CoolQ.user_info(qq)
+        .map(|info| info.nickname)
+        .unwrap_or_else(|err| {
+            debug!("unable to get info of {}: {}", qq, err);
+            qq.to_string()
+        })
+}
+pub fn make_priv_msg_in(qq: i64, content: Msg) -> MsgIn {
     MsgIn::Private {
         qq: qq,
-        alias: alias,
+        alias: alias_of(qq),
         content: content,
     }
 }
-pub fn make_grp_msg_in(grp: i64, qq: i64, content: Msg) -> MsgIn {
-    #[no_mangle]
-    #[link(name="CQP")]
-    extern {
-        #[link_name="CQ_getGroupMemberInfoV2"]
-        fn native (auth: i32, grp: i64, qq: i64, no_cache: i32)
-            -> *const c_char;
+pub fn make_discuss_msg_in(discuss: i64, qq: i64, content: Msg) -> MsgIn {
+    MsgIn::Discuss {
+        discuss: discuss,
+        qq: qq,
+        alias: alias_of(qq),
+        content: content,
     }
-    let b64 = unsafe { CStr::from_ptr(native(AUTH, grp, qq, 0)) };
-    let raw = decode(b64.to_bytes()).unwrap();
-    let mut b = Bytes::from(raw).into_buf();
-
-    b.advance(16);
-    let alias = get_string(&mut b);
-    let grp_alias = get_string(&mut b);
+}
+pub fn make_grp_msg_in(grp: i64, qq: i64, content: Msg,
+                       anon: Option<Anonymous>) -> MsgIn {
+    let (alias, grp_alias) = match anon {
+        // Anonymous members are only known by their names.
+        Some(ref anon) => (anon.name.clone(), anon.name.clone()),
+        None => {
+            CoolQ.member_info(grp, qq)
+                .map(|info| (info.nickname, info.card))
+                .unwrap_or_else(|err| {
+                    debug!("unable to get info of {} in {}: {}",
+                           qq, grp, err);
+                    (qq.to_string(), String::new())
+                })
+        },
+    };
     MsgIn::Group {
         grp: grp,
         qq: qq,
         alias: alias,
         grp_alias: grp_alias,
         content: content,
+        anon: anon,
     }
 }
 
@@ -111,39 +451,52 @@ pub extern "stdcall" fn native_app() -> *const u8 {
 #[no_mangle]
 #[export_name = "Initialize"]
 pub extern "stdcall" fn native_init(auth: i32) -> i32 {
-    unsafe { AUTH = auth; }
-    0
+    guard_fatal("native_init", || {
+        unsafe { AUTH = auth; }
+        // CoolQ filters by priority itself, so everything is forwarded. A
+        // logger could only have been set if the DLL is initialized twice.
+        if log::set_logger(&COOLQ_LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+        }
+        0
+    })
 }
 #[no_mangle]
 pub extern "stdcall" fn native_launch() -> i32 {
-    ::on_launch();
-    let mut dispatcher = Dispatcher::new();
-    ::on_configure(&mut dispatcher);
-    unsafe { DISPATCHER = Some(dispatcher); }
-    0
+    guard_fatal("native_launch", || {
+        ::on_launch();
+        let mut dispatcher = Dispatcher::new();
+        let rv = app_dir().and_then(|app_dir| {
+            let composer = CoolQComposer::new(&coolq_dir(&app_dir)?);
+            ::on_configure(&mut dispatcher, &app_dir, composer)
+        });
+        if let Err(err) = rv {
+            // Leave the dispatcher unset, so that all events are ignored,
+            // and let CoolQ tell the user.
+            let info = format!("unable to configure: {}", err);
+            error!("{}", info);
+            set_fatal(&info);
+            return 0
+        }
+        unsafe { DISPATCHER = Some(dispatcher); }
+        on_lifecycle(Lifecycle::Launch)
+    })
 }
 #[no_mangle]
 pub extern "stdcall" fn native_shutdown() -> i32 {
-    ::on_shutdown();
-    0
+    guard_fatal("native_shutdown", || {
+        on_lifecycle(Lifecycle::Shutdown);
+        ::on_shutdown();
+        0
+    })
 }
 #[no_mangle]
 pub extern "stdcall" fn native_enable() -> i32 {
-    unsafe {
-        if let Some(dispatcher) = DISPATCHER.as_mut() {
-            dispatcher.enable();
-        }
-    }
-    0
+    guard_fatal("native_enable", || on_lifecycle(Lifecycle::Enable))
 }
 #[no_mangle]
 pub extern "stdcall" fn native_disable() -> i32 {
-    unsafe {
-        if let Some(dispatcher) = DISPATCHER.as_mut() {
-            dispatcher.disable();
-        }
-    }
-    0
+    guard_fatal("native_disable", || on_lifecycle(Lifecycle::Disable))
 }
 #[no_mangle]
 pub extern "stdcall" fn native_on_recv_priv(subtype: i32,
@@ -151,17 +504,21 @@ pub extern "stdcall" fn native_on_recv_priv(subtype: i32,
                                             from_qq: i64,
                                             msg: *const c_char,
                                             font: i32) -> i32 {
-    let raw = unsafe { CStr::from_ptr(msg) };
-    use encoding_rs::GB18030;
-    let (decoded, _) = GB18030.decode_without_bom_handling(raw.to_bytes());
-    unsafe {
-        if let Some(dispatcher) = DISPATCHER.as_ref() {
-            let msg = dispatcher.composer().decompose(&decoded).unwrap();
+    // The message is left to other plugins if the bot panics.
+    unwind::guard("native_on_recv_priv", consts::EVENT_IGNORE, || {
+        let decoded = decode(msg);
+        with_dispatcher(|dispatcher| {
+            let msg = match dispatcher.composer().decompose(&decoded) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    error!("unable to decompose {:?}: {}", decoded, err);
+                    return consts::EVENT_IGNORE
+                },
+            };
             let msg_in = make_priv_msg_in(from_qq, msg);
-            dispatcher.dispatch(msg_in);
-        }
-    }
-    consts::EVENT_IGNORE
+            handle(dispatcher, &msg_in)
+        })
+    })
 }
 #[no_mangle]
 pub extern "stdcall" fn native_on_recv_grp(subtype: i32,
@@ -171,16 +528,177 @@ pub extern "stdcall" fn native_on_recv_grp(subtype: i32,
                                         from_anon: *const c_char,
                                         msg: *const c_char,
                                         font: i32) -> i32 {
-    // Ignore anonymous instructions.
-    if !from_anon.is_null() { return consts::EVENT_IGNORE }
-    let raw = unsafe { CStr::from_ptr(msg) };
-    let (decoded, _) = GB18030.decode_without_bom_handling(raw.to_bytes());
-    unsafe {
-        if let Some(dispatcher) = DISPATCHER.as_ref() {
-            let msg = dispatcher.composer().decompose(&decoded).unwrap();
-            let msg_in = make_grp_msg_in(from_grp, from_qq, msg);
-            dispatcher.dispatch(msg_in);
-        }
-    }
-    consts::EVENT_IGNORE
+    unwind::guard("native_on_recv_grp", consts::EVENT_IGNORE, || {
+        // The info is empty for messages that are not anonymous.
+        let anon = match check_str("anonymous info", from_anon) {
+            Ok(ref b64) if !b64.is_empty() => match parse_anonymous(b64) {
+                Ok(anon) => Some(anon),
+                Err(err) => {
+                    error!("unable to parse anonymous info: {}", err);
+                    return consts::EVENT_IGNORE
+                },
+            },
+            _ => None,
+        };
+        let decoded = decode(msg);
+        with_dispatcher(|dispatcher| {
+            let msg = match dispatcher.composer().decompose(&decoded) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    error!("unable to decompose {:?}: {}", decoded, err);
+                    return consts::EVENT_IGNORE
+                },
+            };
+            let msg_in = make_grp_msg_in(from_grp, from_qq, msg, anon);
+            handle(dispatcher, &msg_in)
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_recv_discuss(_subtype: i32,
+                                               _msg_id: i32,
+                                               from_discuss: i64,
+                                               from_qq: i64,
+                                               msg: *const c_char,
+                                               _font: i32) -> i32 {
+    unwind::guard("native_on_recv_discuss", consts::EVENT_IGNORE, || {
+        let decoded = decode(msg);
+        with_dispatcher(|dispatcher| {
+            let msg = match dispatcher.composer().decompose(&decoded) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    error!("unable to decompose {:?}: {}", decoded, err);
+                    return consts::EVENT_IGNORE
+                },
+            };
+            let msg_in = make_discuss_msg_in(from_discuss, from_qq, msg);
+            handle(dispatcher, &msg_in)
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_upload(_subtype: i32,
+                                             _send_time: i32,
+                                             from_grp: i64,
+                                             from_qq: i64,
+                                             file: *const c_char) -> i32 {
+    unwind::guard("native_on_grp_upload", consts::EVENT_IGNORE, || {
+        let info = check_str("file info", file)
+            .and_then(|b64| parse_file_info(&b64));
+        let info = match info {
+            Ok(info) => info,
+            Err(err) => {
+                error!("unable to parse uploaded file info: {}", err);
+                return consts::EVENT_IGNORE
+            },
+        };
+        on_event(Event::GroupUpload {
+            grp: from_grp,
+            qq: from_qq,
+            file: info,
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_admin(subtype: i32,
+                                            _send_time: i32,
+                                            from_grp: i64,
+                                            target_qq: i64) -> i32 {
+    unwind::guard("native_on_grp_admin", consts::EVENT_IGNORE, || {
+        on_event(Event::AdminChange {
+            grp: from_grp,
+            qq: target_qq,
+            set: subtype == 2,
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_member_leave(subtype: i32,
+                                                   _send_time: i32,
+                                                   from_grp: i64,
+                                                   from_qq: i64,
+                                                   target_qq: i64) -> i32 {
+    unwind::guard("native_on_grp_member_leave", consts::EVENT_IGNORE, || {
+        // Members leave on their own with sub-type 1, otherwise they are
+        // kicked.
+        on_event(Event::MemberLeave {
+            grp: from_grp,
+            qq: target_qq,
+            operator: if subtype == 1 { None } else { Some(from_qq) },
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_member_join(subtype: i32,
+                                                  _send_time: i32,
+                                                  from_grp: i64,
+                                                  from_qq: i64,
+                                                  target_qq: i64) -> i32 {
+    unwind::guard("native_on_grp_member_join", consts::EVENT_IGNORE, || {
+        on_event(Event::MemberJoin {
+            grp: from_grp,
+            qq: target_qq,
+            operator: from_qq,
+            invited: subtype == 2,
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_ban(subtype: i32,
+                                          _send_time: i32,
+                                          from_grp: i64,
+                                          from_qq: i64,
+                                          target_qq: i64,
+                                          duration: i64) -> i32 {
+    unwind::guard("native_on_grp_ban", consts::EVENT_IGNORE, || {
+        on_event(Event::Ban {
+            grp: from_grp,
+            // The whole group is banned without a target.
+            qq: if target_qq == 0 { None } else { Some(target_qq) },
+            operator: from_qq,
+            // Sub-type 1 lifts the ban.
+            duration: if subtype == 1 { 0 } else { duration },
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_friend_added(_subtype: i32,
+                                               _send_time: i32,
+                                               from_qq: i64) -> i32 {
+    unwind::guard("native_on_friend_added", consts::EVENT_IGNORE, || {
+        on_event(Event::FriendAdded { qq: from_qq })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_friend_req(_subtype: i32,
+                                             _send_time: i32,
+                                             from_qq: i64,
+                                             msg: *const c_char,
+                                             flag: *const c_char) -> i32 {
+    unwind::guard("native_on_friend_req", consts::EVENT_IGNORE, || {
+        on_event(Event::FriendRequest {
+            qq: from_qq,
+            comment: decode(msg),
+            flag: decode(flag),
+        })
+    })
+}
+#[no_mangle]
+pub extern "stdcall" fn native_on_grp_req(subtype: i32,
+                                          _send_time: i32,
+                                          from_grp: i64,
+                                          from_qq: i64,
+                                          msg: *const c_char,
+                                          flag: *const c_char) -> i32 {
+    unwind::guard("native_on_grp_req", consts::EVENT_IGNORE, || {
+        on_event(Event::GroupRequest {
+            grp: from_grp,
+            qq: from_qq,
+            comment: decode(msg),
+            flag: decode(flag),
+            // Sub-type 1 is someone applying to join, and 2 is the bot
+            // being invited.
+            invited: subtype == 2,
+        })
+    })
 }
@@ -0,0 +1,58 @@
+//! Containment of panics. A panic unwinding across `extern "stdcall"` takes
+//! CoolQ down with the bot, so panics are caught where they can be told
+//! apart, in each backend and middleware, and at last at each exported
+//! function.
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use msg::Msg;
+
+/// Message a panic is raised with, if it's a string as it usually is.
+pub fn message(payload: &(Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        (*msg).to_owned()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Run `f`, catching and logging its panic. The panic message is returned
+/// if it panics.
+///
+/// `f` is assumed to leave nothing broken behind when it panics halfway.
+pub fn catch<F, T>(name: &str, f: F) -> Result<T, String>
+        where F: FnOnce() -> T {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| {
+            let msg = message(&*payload);
+            error!("{} panicked: {}", name, msg);
+            msg
+        })
+}
+
+/// Run `f` on behalf of an exported function, returning `fallback` if it
+/// panics.
+pub fn guard<F>(name: &str, fallback: i32, f: F) -> i32
+        where F: FnOnce() -> i32 {
+    catch(name, f).unwrap_or(fallback)
+}
+
+/// Tell the user something went wrong, without the details.
+pub fn apology() -> Msg {
+    ::msg::text("Oops, something went wrong.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard() {
+        ::logger::init_test();
+        assert_eq!(guard("test", -1, || 1), 1);
+        assert_eq!(guard("test", -1, || panic!("boom")), -1);
+        assert_eq!(catch("test", || panic!("boom {}", 1)),
+                   Err::<(), _>("boom 1".to_owned()));
+    }
+}